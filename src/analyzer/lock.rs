@@ -0,0 +1,375 @@
+use super::{LockMode, StatementLock};
+use crate::parser::{utils::node_to_string, SchemaId};
+use anyhow::{anyhow, Result};
+use itertools::Itertools;
+use pg_query::{
+    protobuf::{
+        AlterTableCmd, AlterTableStmt, AlterTableType, ConstrType, CreateStmt, DropStmt, ObjectType,
+    },
+    Node, NodeEnum, NodeRef,
+};
+use std::{collections::BTreeSet, fmt};
+
+/// functions which make `ADD COLUMN ... DEFAULT` rewrite the whole table
+const VOLATILE_FUNCTIONS: [&str; 6] = [
+    "random",
+    "gen_random_uuid",
+    "uuid_generate_v4",
+    "clock_timestamp",
+    "timeofday",
+    "nextval",
+];
+
+impl LockMode {
+    /// a strong lock blocks concurrent writes to the table
+    pub fn is_strong(&self) -> bool {
+        *self >= LockMode::Share
+    }
+}
+
+impl StatementLock {
+    /// analyze the lock a single SQL statement acquires
+    pub fn analyze(sql: &str) -> Result<Self> {
+        let parsed = pg_query::parse(sql)?;
+        let node = parsed
+            .protobuf
+            .nodes()
+            .first()
+            .map(|(node, _, _)| *node)
+            .ok_or_else(|| anyhow!("no statement found: {}", sql))?;
+
+        let lock = match node {
+            NodeRef::AlterTableStmt(stmt) => alter_table_lock(stmt),
+            NodeRef::IndexStmt(stmt) => {
+                let mode = if stmt.concurrent {
+                    LockMode::ShareUpdateExclusive
+                } else {
+                    LockMode::Share
+                };
+                Self::new(
+                    mode,
+                    stmt.relation.iter().map(|v| SchemaId::from(v).to_string()),
+                )
+            }
+            NodeRef::DropStmt(stmt) => drop_lock(stmt),
+            NodeRef::CreateStmt(stmt) => create_table_lock(stmt),
+            NodeRef::CreateTrigStmt(stmt) => Self::new(
+                LockMode::ShareRowExclusive,
+                stmt.relation.iter().map(|v| SchemaId::from(v).to_string()),
+            ),
+            NodeRef::CreatePolicyStmt(stmt) => Self::new(
+                LockMode::AccessExclusive,
+                stmt.table.iter().map(|v| SchemaId::from(v).to_string()),
+            ),
+            NodeRef::RenameStmt(stmt) => Self::new(
+                LockMode::AccessExclusive,
+                stmt.relation.iter().map(|v| SchemaId::from(v).to_string()),
+            ),
+            NodeRef::ViewStmt(stmt) if stmt.replace => Self::new(
+                LockMode::AccessExclusive,
+                stmt.view.iter().map(|v| SchemaId::from(v).to_string()),
+            ),
+            NodeRef::AlterSeqStmt(stmt) => Self::new(
+                LockMode::ShareRowExclusive,
+                stmt.sequence.iter().map(|v| SchemaId::from(v).to_string()),
+            ),
+            NodeRef::UpdateStmt(stmt) => Self::new(
+                LockMode::RowExclusive,
+                stmt.relation.iter().map(|v| SchemaId::from(v).to_string()),
+            ),
+            _ => Self::default(),
+        };
+
+        Ok(lock)
+    }
+
+    /// analyze all statements of a plan. Relations created earlier in the same plan are not
+    /// reported since nobody else could use them yet.
+    pub fn analyze_all(plan: &[String]) -> Result<Vec<Self>> {
+        let mut created = BTreeSet::new();
+        let mut locks = Vec::with_capacity(plan.len());
+        for sql in plan {
+            let mut lock = Self::analyze(sql)?;
+            lock.tables.retain(|t| !created.contains(t));
+            if lock.tables.is_empty() {
+                lock = Self::default();
+            }
+            created.extend(created_relations(sql)?);
+            locks.push(lock);
+        }
+        Ok(locks)
+    }
+
+    /// check if the statement acquires a strong lock on any of the given tables
+    pub fn is_strong_on(&self, table: impl Fn(&str) -> bool) -> bool {
+        matches!(self.mode, Some(mode) if mode.is_strong())
+            && self.tables.iter().any(|t| table(t.as_str()))
+    }
+
+    fn new(mode: LockMode, tables: impl Iterator<Item = String>) -> Self {
+        Self {
+            mode: Some(mode),
+            tables: tables.collect(),
+            rewrite: false,
+        }
+    }
+}
+
+impl fmt::Display for LockMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            LockMode::AccessShare => "ACCESS SHARE",
+            LockMode::RowShare => "ROW SHARE",
+            LockMode::RowExclusive => "ROW EXCLUSIVE",
+            LockMode::ShareUpdateExclusive => "SHARE UPDATE EXCLUSIVE",
+            LockMode::Share => "SHARE",
+            LockMode::ShareRowExclusive => "SHARE ROW EXCLUSIVE",
+            LockMode::Exclusive => "EXCLUSIVE",
+            LockMode::AccessExclusive => "ACCESS EXCLUSIVE",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl fmt::Display for StatementLock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.mode {
+            Some(mode) => {
+                write!(f, "{} lock on {}", mode, self.tables.join(", "))?;
+                if self.rewrite {
+                    write!(f, " (table rewrite)")?;
+                }
+                Ok(())
+            }
+            None => write!(f, "no lock on existing tables"),
+        }
+    }
+}
+
+fn alter_table_lock(stmt: &AlterTableStmt) -> StatementLock {
+    let mut tables: Vec<String> = stmt
+        .relation
+        .iter()
+        .map(|v| SchemaId::from(v).to_string())
+        .collect();
+    let mut mode = None;
+    let mut rewrite = false;
+
+    for cmd in stmt.cmds.iter().filter_map(|n| n.node.as_ref()) {
+        if let NodeEnum::AlterTableCmd(cmd) = cmd {
+            let (m, r, referenced) = alter_table_cmd_lock(cmd);
+            mode = mode.max(Some(m));
+            rewrite |= r;
+            tables.extend(referenced);
+        }
+    }
+
+    StatementLock {
+        mode,
+        tables: tables.into_iter().unique().collect(),
+        rewrite,
+    }
+}
+
+/// returns the lock mode, whether it rewrites the table, and the referenced table if any
+fn alter_table_cmd_lock(cmd: &AlterTableCmd) -> (LockMode, bool, Option<String>) {
+    let def = cmd.def.as_ref().and_then(|n| n.node.as_ref());
+    match cmd.subtype() {
+        AlterTableType::AtAddConstraint => match def {
+            Some(NodeEnum::Constraint(c)) if c.contype() == ConstrType::ConstrForeign => (
+                LockMode::ShareRowExclusive,
+                false,
+                c.pktable.as_ref().map(|v| SchemaId::from(v).to_string()),
+            ),
+            _ => (LockMode::AccessExclusive, false, None),
+        },
+        AlterTableType::AtValidateConstraint
+        | AlterTableType::AtSetStatistics
+        | AlterTableType::AtSetOptions
+        | AlterTableType::AtResetOptions
+        | AlterTableType::AtClusterOn
+        | AlterTableType::AtDropCluster => (LockMode::ShareUpdateExclusive, false, None),
+        AlterTableType::AtEnableTrig
+        | AlterTableType::AtDisableTrig
+        | AlterTableType::AtEnableTrigAll
+        | AlterTableType::AtDisableTrigAll => (LockMode::ShareRowExclusive, false, None),
+        AlterTableType::AtAlterColumnType | AlterTableType::AtSetTableSpace => {
+            (LockMode::AccessExclusive, true, None)
+        }
+        AlterTableType::AtAddColumn => {
+            let rewrite = match def {
+                Some(NodeEnum::ColumnDef(col)) => col.constraints.iter().any(is_rewrite_constraint),
+                _ => false,
+            };
+            (LockMode::AccessExclusive, rewrite, None)
+        }
+        _ => (LockMode::AccessExclusive, false, None),
+    }
+}
+
+/// identity / generated columns, or defaults with volatile functions need a table rewrite
+fn is_rewrite_constraint(node: &Node) -> bool {
+    match &node.node {
+        Some(NodeEnum::Constraint(c)) => match c.contype() {
+            ConstrType::ConstrIdentity | ConstrType::ConstrGenerated => true,
+            ConstrType::ConstrDefault => match c.raw_expr.as_ref().and_then(|n| n.node.as_ref()) {
+                Some(NodeEnum::FuncCall(f)) => f
+                    .funcname
+                    .last()
+                    .and_then(node_to_string)
+                    .map(|name| VOLATILE_FUNCTIONS.contains(&name.as_str()))
+                    .unwrap_or(false),
+                _ => false,
+            },
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+fn drop_lock(stmt: &DropStmt) -> StatementLock {
+    let names = stmt.objects.iter().filter_map(object_names);
+    match stmt.remove_type() {
+        ObjectType::ObjectIndex if stmt.concurrent => StatementLock::new(
+            LockMode::ShareUpdateExclusive,
+            names.map(|n| qualified_name(&n)),
+        ),
+        ObjectType::ObjectTable
+        | ObjectType::ObjectIndex
+        | ObjectType::ObjectView
+        | ObjectType::ObjectMatview
+        | ObjectType::ObjectSequence => {
+            StatementLock::new(LockMode::AccessExclusive, names.map(|n| qualified_name(&n)))
+        }
+        // for trigger and policy, the last name is the object, the rest is the table
+        ObjectType::ObjectTrigger | ObjectType::ObjectPolicy => StatementLock::new(
+            LockMode::AccessExclusive,
+            names.map(|n| qualified_name(&n[..n.len().saturating_sub(1)])),
+        ),
+        _ => StatementLock::default(),
+    }
+}
+
+fn create_table_lock(stmt: &CreateStmt) -> StatementLock {
+    let mut constraints = Vec::new();
+    for node in stmt.table_elts.iter().filter_map(|n| n.node.as_ref()) {
+        match node {
+            NodeEnum::Constraint(c) => constraints.push(c.as_ref()),
+            NodeEnum::ColumnDef(col) => {
+                constraints.extend(col.constraints.iter().filter_map(|n| match &n.node {
+                    Some(NodeEnum::Constraint(c)) => Some(c.as_ref()),
+                    _ => None,
+                }))
+            }
+            _ => {}
+        }
+    }
+
+    // a new table only locks the tables it references
+    let referenced: Vec<_> = constraints
+        .into_iter()
+        .filter(|c| c.contype() == ConstrType::ConstrForeign)
+        .filter_map(|c| c.pktable.as_ref().map(|v| SchemaId::from(v).to_string()))
+        .unique()
+        .collect();
+
+    if referenced.is_empty() {
+        StatementLock::default()
+    } else {
+        StatementLock::new(LockMode::ShareRowExclusive, referenced.into_iter())
+    }
+}
+
+pub(super) fn created_relations(sql: &str) -> Result<Vec<String>> {
+    let parsed = pg_query::parse(sql)?;
+    let relations: Vec<SchemaId> = match parsed.protobuf.nodes().first().map(|(node, _, _)| *node) {
+        Some(NodeRef::CreateStmt(stmt)) => stmt.relation.iter().map(SchemaId::from).collect(),
+        Some(NodeRef::CreateSeqStmt(stmt)) => stmt.sequence.iter().map(SchemaId::from).collect(),
+        Some(NodeRef::ViewStmt(stmt)) if !stmt.replace => {
+            stmt.view.iter().map(SchemaId::from).collect()
+        }
+        Some(NodeRef::CreateTableAsStmt(stmt)) => stmt
+            .into
+            .iter()
+            .filter_map(|v| v.rel.as_ref())
+            .map(SchemaId::from)
+            .collect(),
+        _ => vec![],
+    };
+    Ok(relations.into_iter().map(|id| id.to_string()).collect())
+}
+
+fn object_names(node: &Node) -> Option<Vec<String>> {
+    match &node.node {
+        Some(NodeEnum::List(list)) => Some(list.items.iter().filter_map(node_to_string).collect()),
+        Some(NodeEnum::String(s)) => Some(vec![s.str.clone()]),
+        _ => None,
+    }
+}
+
+fn qualified_name(names: &[String]) -> String {
+    let names: Vec<_> = names.iter().map(|s| s.as_str()).collect();
+    SchemaId::new_with(&names).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn add_column_should_take_access_exclusive_lock() {
+        let sql = "ALTER TABLE ONLY public.todos ADD COLUMN created_at timestamptz";
+        let lock = StatementLock::analyze(sql).unwrap();
+        assert_eq!(lock.mode, Some(LockMode::AccessExclusive));
+        assert_eq!(lock.tables, vec!["public.todos"]);
+        assert!(!lock.rewrite);
+    }
+
+    #[test]
+    fn change_column_type_should_rewrite_table() {
+        let sql = "ALTER TABLE public.todos ALTER COLUMN title TYPE varchar(256)";
+        let lock = StatementLock::analyze(sql).unwrap();
+        assert_eq!(lock.mode, Some(LockMode::AccessExclusive));
+        assert!(lock.rewrite);
+        assert_eq!(
+            lock.to_string(),
+            "ACCESS EXCLUSIVE lock on public.todos (table rewrite)"
+        );
+    }
+
+    #[test]
+    fn add_foreign_key_should_lock_both_tables() {
+        let sql = "ALTER TABLE ONLY public.orders ADD CONSTRAINT orders_user_id_fkey FOREIGN KEY (user_id) REFERENCES public.users(id)";
+        let lock = StatementLock::analyze(sql).unwrap();
+        assert_eq!(lock.mode, Some(LockMode::ShareRowExclusive));
+        assert_eq!(lock.tables, vec!["public.orders", "public.users"]);
+    }
+
+    #[test]
+    fn create_index_concurrently_should_not_block_writes() {
+        let lock = StatementLock::analyze("CREATE INDEX todos_title_idx ON todos (title)").unwrap();
+        assert_eq!(lock.mode, Some(LockMode::Share));
+        assert!(lock.mode.unwrap().is_strong());
+
+        let sql = "CREATE INDEX CONCURRENTLY todos_title_idx ON todos (title)";
+        let lock = StatementLock::analyze(sql).unwrap();
+        assert_eq!(lock.mode, Some(LockMode::ShareUpdateExclusive));
+        assert!(!lock.mode.unwrap().is_strong());
+        assert_eq!(lock.tables, vec!["public.todos"]);
+    }
+
+    #[test]
+    fn relations_created_in_the_plan_should_be_ignored() {
+        let plan = vec![
+            "CREATE TABLE public.todos (id bigint NOT NULL, title text)".to_owned(),
+            "ALTER TABLE ONLY public.todos ADD CONSTRAINT todos_pkey PRIMARY KEY (id)".to_owned(),
+            "DROP TABLE public.users".to_owned(),
+        ];
+        let locks = StatementLock::analyze_all(&plan).unwrap();
+        assert_eq!(locks.len(), 3);
+        assert_eq!(locks[0].mode, None);
+        assert_eq!(locks[1].mode, None);
+        assert_eq!(locks[2].mode, Some(LockMode::AccessExclusive));
+        assert_eq!(locks[2].tables, vec!["public.users"]);
+    }
+}
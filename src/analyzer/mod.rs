@@ -0,0 +1,100 @@
+mod lock;
+mod phase;
+mod preflight;
+mod risk;
+mod transaction;
+
+pub(crate) use transaction::is_transactional;
+
+use serde::{Deserialize, Serialize};
+
+/// Table level lock modes in postgres, ordered from the weakest to the strongest
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LockMode {
+    AccessShare,
+    RowShare,
+    RowExclusive,
+    ShareUpdateExclusive,
+    Share,
+    ShareRowExclusive,
+    Exclusive,
+    AccessExclusive,
+}
+
+/// The lock a migration statement acquires on existing relations
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StatementLock {
+    /// the strongest lock mode acquired. None if the statement doesn't lock any existing relation
+    pub mode: Option<LockMode>,
+    /// schema qualified names of the locked relations
+    pub tables: Vec<String>,
+    /// whether the statement rewrites the table
+    pub rewrite: bool,
+}
+
+/// Risk level of a migration step
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RiskLevel {
+    /// no existing data or concurrent access is affected
+    Low,
+    /// blocks concurrent access or rewrites the table
+    Medium,
+    /// might lose data
+    High,
+}
+
+/// When a step is applied relative to deploying the app, so that the old and the new app both
+/// work with the schema in between
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum MigrationPhase {
+    /// additive, backward compatible steps, applied before deploying the app
+    #[default]
+    Pre,
+    /// drops and tightening steps the old app might break on, applied after deploying the app
+    Post,
+}
+
+/// A read-only query run against the target before applying, which finds the existing rows a
+/// step of the plan would fail on
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PreflightCheck {
+    /// index of the checked step in the plan
+    pub step: usize,
+    /// what the query counts, e.g. `NULL value(s) in public.todos.title`
+    pub description: String,
+    pub query: PreflightQuery,
+}
+
+/// How a preflight check finds the offending rows
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PreflightQuery {
+    /// a query returning the number of offending rows
+    Count(String),
+    /// a query converting every value to the new type, which fails if any value can't be
+    Cast(String),
+    /// the enum type is recreated: count the values in use which are not in `values`
+    EnumValues {
+        type_name: String,
+        values: Vec<String>,
+    },
+}
+
+/// The outcome of a preflight check against the target database
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PreflightResult {
+    pub check: PreflightCheck,
+    /// number of the offending rows. None if the query failed
+    pub count: Option<i64>,
+    /// the error of the query, e.g. a column added earlier in the plan doesn't exist yet
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// the SQLSTATE of the error, e.g. `22P02` for a value which can't be converted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sql_state: Option<String>,
+}
@@ -0,0 +1,80 @@
+use super::MigrationPhase;
+use anyhow::{bail, Result};
+use pg_query::{protobuf::AlterTableType, NodeEnum, NodeRef};
+use std::{fmt, str::FromStr};
+
+impl MigrationPhase {
+    /// the phase of a single statement. Dropping objects or columns and `SET NOT NULL` break
+    /// the old app, so they're applied after the new one is deployed
+    pub fn of(sql: &str) -> Result<Self> {
+        let parsed = pg_query::parse(sql)?;
+        let contracting = match parsed.protobuf.nodes().first().map(|(node, _, _)| *node) {
+            Some(NodeRef::DropStmt(_)) => true,
+            Some(NodeRef::AlterTableStmt(stmt)) => stmt.cmds.iter().any(|n| {
+                matches!(
+                    &n.node,
+                    Some(NodeEnum::AlterTableCmd(cmd)) if matches!(
+                        cmd.subtype(),
+                        AlterTableType::AtDropColumn | AlterTableType::AtSetNotNull
+                    )
+                )
+            }),
+            _ => false,
+        };
+        Ok(if contracting {
+            MigrationPhase::Post
+        } else {
+            MigrationPhase::Pre
+        })
+    }
+}
+
+impl fmt::Display for MigrationPhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            MigrationPhase::Pre => "pre",
+            MigrationPhase::Post => "post",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl FromStr for MigrationPhase {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "pre" => Ok(MigrationPhase::Pre),
+            "post" => Ok(MigrationPhase::Post),
+            _ => bail!("invalid phase: {}. Expected pre or post", s),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn contracting_statements_should_be_post_deploy() {
+        for sql in [
+            "DROP TABLE public.todos",
+            "ALTER TABLE public.todos DROP COLUMN title",
+            "ALTER TABLE ONLY public.todos ALTER COLUMN title SET NOT NULL",
+        ] {
+            assert_eq!(MigrationPhase::of(sql).unwrap(), MigrationPhase::Post);
+        }
+        for sql in [
+            "CREATE TABLE public.users (id int)",
+            "ALTER TABLE ONLY public.todos ADD COLUMN due date",
+            "ALTER TABLE ONLY public.todos ALTER COLUMN title DROP NOT NULL",
+        ] {
+            assert_eq!(MigrationPhase::of(sql).unwrap(), MigrationPhase::Pre);
+        }
+        assert_eq!(
+            "post".parse::<MigrationPhase>().unwrap(),
+            MigrationPhase::Post
+        );
+        assert!("contract".parse::<MigrationPhase>().is_err());
+    }
+}
@@ -0,0 +1,612 @@
+use super::{lock::created_relations, PreflightCheck, PreflightQuery, PreflightResult};
+use crate::{
+    normalizer::{parse_stmt, qualified_name, quote_ident},
+    parser::{utils::node_to_string, SchemaId},
+};
+use anyhow::Result;
+use itertools::Itertools;
+use pg_query::{
+    protobuf::{
+        AlterTableStmt, AlterTableType, ColumnDef, ConstrType, Constraint, DropStmt, IndexStmt,
+        ObjectType, TypeName,
+    },
+    Node, NodeEnum,
+};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+};
+
+/// what a check finds, and the query to find it
+type Check = (String, PreflightQuery);
+
+impl PreflightCheck {
+    /// find the checks for the statements of a plan. Tables created earlier in the same plan
+    /// have no rows yet, and columns added earlier in the plan are checked when they are added.
+    /// Conversions to a type the plan creates can't run before the plan, so they're not checked
+    pub fn analyze_all(plan: &[String]) -> Result<Vec<Self>> {
+        let enums = created_enums(plan)?;
+        let types = created_types(plan)?;
+        let mut tables = PlanTables::default();
+        let mut checks = Vec::new();
+        for (step, sql) in plan.iter().enumerate() {
+            let found = match parse_stmt(sql)? {
+                NodeEnum::AlterTableStmt(stmt) => alter_table_checks(&stmt, &mut tables, &types),
+                NodeEnum::IndexStmt(stmt) => unique_index_checks(&stmt, &tables),
+                NodeEnum::DropStmt(stmt) => enum_checks(&stmt, &enums),
+                _ => vec![],
+            };
+            checks.extend(found.into_iter().map(|(description, query)| Self {
+                step,
+                description,
+                query,
+            }));
+            tables.created.extend(created_relations(sql)?);
+        }
+        Ok(checks)
+    }
+}
+
+impl PreflightResult {
+    /// whether the check found rows the step would fail on
+    pub fn blocks(&self) -> bool {
+        match (&self.check.query, self.count, &self.sql_state) {
+            (_, Some(count), _) => count > 0,
+            // the conversion failed on some value: a data exception (class 22) or an integrity
+            // constraint violation (class 23). Any other error means the check couldn't run
+            (PreflightQuery::Cast(_), None, Some(state)) => {
+                state.starts_with("22") || state.starts_with("23")
+            }
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for PreflightResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let check = &self.check;
+        let status = match (self.blocks(), &self.error) {
+            (true, _) => "FAIL",
+            (false, Some(_)) => "SKIP",
+            (false, None) => "OK",
+        };
+        write!(f, "[{}] statement {}: ", status, check.step + 1)?;
+        match (self.count, &self.error) {
+            (Some(count), _) => write!(f, "{} {}", count, check.description),
+            (None, Some(e)) if status == "FAIL" => write!(f, "{}: {}", check.description, e),
+            // SQLSTATE 57014: query_canceled, raised when `statement_timeout` is exceeded
+            (None, Some(_)) if self.sql_state.as_deref() == Some("57014") => write!(
+                f,
+                "check for {} timed out, raise `apply.statement_timeout` to run it",
+                check.description
+            ),
+            (None, e) => write!(
+                f,
+                "check could not run for {}: {}",
+                check.description,
+                e.as_deref().unwrap_or_default()
+            ),
+        }
+    }
+}
+
+/// the tables created and the columns added by the plan so far
+#[derive(Debug, Default)]
+struct PlanTables {
+    created: BTreeSet<String>,
+    added_columns: BTreeSet<String>,
+}
+
+impl PlanTables {
+    fn is_new(&self, table: &str, columns: &[String]) -> bool {
+        self.created.contains(table)
+            || columns
+                .iter()
+                .any(|c| self.added_columns.contains(&format!("{}.{}", table, c)))
+    }
+}
+
+fn alter_table_checks(
+    stmt: &AlterTableStmt,
+    tables: &mut PlanTables,
+    types: &BTreeSet<String>,
+) -> Vec<Check> {
+    let relation = stmt.relation.as_ref();
+    let id = SchemaId::from(relation).to_string();
+    let table = qualified_name(relation);
+    let mut checks = Vec::new();
+
+    for cmd in stmt.cmds.iter().filter_map(|n| n.node.as_ref()) {
+        let cmd = match cmd {
+            NodeEnum::AlterTableCmd(cmd) => cmd,
+            _ => continue,
+        };
+        let def = cmd.def.as_ref().and_then(|n| n.node.as_ref());
+        let column = [cmd.name.clone()];
+        match (cmd.subtype(), def) {
+            (AlterTableType::AtAddColumn, Some(NodeEnum::ColumnDef(col))) => {
+                if !tables.created.contains(&id) && needs_value(col) {
+                    checks.push((
+                        format!(
+                            "row(s) in {}, which would be NULL in the new NOT NULL column {}",
+                            id, col.colname
+                        ),
+                        PreflightQuery::Count(format!("SELECT count(*) FROM {}", table)),
+                    ));
+                }
+                tables
+                    .added_columns
+                    .insert(format!("{}.{}", id, col.colname));
+            }
+            (AlterTableType::AtSetNotNull, _) if !tables.is_new(&id, &column) => {
+                checks.push(null_check(&id, &table, &column));
+            }
+            (AlterTableType::AtAlterColumnType, Some(NodeEnum::ColumnDef(col)))
+                if !tables.is_new(&id, &column) =>
+            {
+                // a `USING` expression decides the conversion itself
+                if col.raw_default.is_none() {
+                    if let Some(type_name) = &col.type_name {
+                        if !types.contains(&type_id(&type_name.names).to_string()) {
+                            checks.extend(type_check(&id, &table, &cmd.name, type_name));
+                        }
+                    }
+                }
+            }
+            (AlterTableType::AtAddConstraint, Some(NodeEnum::Constraint(c))) => {
+                // `NOT VALID` constraints don't check the existing rows
+                if !c.skip_validation {
+                    checks.extend(constraint_checks(&id, &table, c, tables));
+                }
+            }
+            _ => {}
+        }
+    }
+    checks
+}
+
+/// a new NOT NULL column without a default fails on any existing row
+fn needs_value(col: &ColumnDef) -> bool {
+    let types: Vec<_> = col
+        .constraints
+        .iter()
+        .filter_map(|n| match &n.node {
+            Some(NodeEnum::Constraint(c)) => Some(c.contype()),
+            _ => None,
+        })
+        .collect();
+    let not_null = types
+        .iter()
+        .any(|t| matches!(t, ConstrType::ConstrNotnull | ConstrType::ConstrPrimary));
+    let filled = types.iter().any(|t| {
+        matches!(
+            t,
+            ConstrType::ConstrDefault | ConstrType::ConstrIdentity | ConstrType::ConstrGenerated
+        )
+    });
+    not_null && !filled
+}
+
+fn constraint_checks(id: &str, table: &str, c: &Constraint, tables: &PlanTables) -> Vec<Check> {
+    match c.contype() {
+        ConstrType::ConstrPrimary | ConstrType::ConstrUnique => {
+            let keys = names(&c.keys);
+            // `USING INDEX` constraints reuse an index which is already unique
+            if keys.is_empty() || tables.is_new(id, &keys) {
+                return vec![];
+            }
+            let mut checks = vec![duplicate_check(id, table, &keys, None)];
+            if c.contype() == ConstrType::ConstrPrimary {
+                checks.push(null_check(id, table, &keys));
+            }
+            checks
+        }
+        ConstrType::ConstrCheck => match c.raw_expr.as_deref() {
+            Some(expr) if !tables.created.contains(id) => match check_query(table, expr) {
+                Ok(sql) => vec![(
+                    format!("row(s) in {} violating the check {}", id, c.conname),
+                    PreflightQuery::Count(sql),
+                )],
+                Err(_) => vec![],
+            },
+            _ => vec![],
+        },
+        ConstrType::ConstrForeign => {
+            let fk_attrs = names(&c.fk_attrs);
+            let pk_attrs = names(&c.pk_attrs);
+            if fk_attrs.len() != pk_attrs.len() || tables.is_new(id, &fk_attrs) {
+                return vec![];
+            }
+            let pktable = c.pktable.as_ref();
+            let referenced = SchemaId::from(pktable).to_string();
+            // rows with a NULL in the key are not checked
+            let mut conditions: Vec<_> = fk_attrs
+                .iter()
+                .map(|col| format!("c.{} IS NOT NULL", quote_ident(col)))
+                .collect();
+            // a table created earlier in the plan has no row to reference
+            if !tables.created.contains(&referenced) {
+                let matches = fk_attrs
+                    .iter()
+                    .zip(pk_attrs.iter())
+                    .map(|(fk, pk)| format!("r.{} = c.{}", quote_ident(pk), quote_ident(fk)))
+                    .join(" AND ");
+                conditions.push(format!(
+                    "NOT EXISTS (SELECT 1 FROM {} r WHERE {})",
+                    qualified_name(pktable),
+                    matches
+                ));
+            }
+            vec![(
+                format!("row(s) in {} without a matching row in {}", id, referenced),
+                PreflightQuery::Count(format!(
+                    "SELECT count(*) FROM {} c WHERE {}",
+                    table,
+                    conditions.join(" AND ")
+                )),
+            )]
+        }
+        _ => vec![],
+    }
+}
+
+fn unique_index_checks(stmt: &IndexStmt, tables: &PlanTables) -> Vec<Check> {
+    if !stmt.unique {
+        return vec![];
+    }
+    let relation = stmt.relation.as_ref();
+    let id = SchemaId::from(relation).to_string();
+    // indexes on expressions are not checked
+    let keys: Vec<String> = stmt
+        .index_params
+        .iter()
+        .filter_map(|n| match &n.node {
+            Some(NodeEnum::IndexElem(elem)) if !elem.name.is_empty() => Some(elem.name.clone()),
+            _ => None,
+        })
+        .collect();
+    if keys.len() != stmt.index_params.len() || tables.is_new(&id, &keys) {
+        return vec![];
+    }
+    let predicate = match stmt.where_clause.as_deref() {
+        Some(expr) => match predicate_sql(expr) {
+            Ok(sql) => Some(sql),
+            Err(_) => return vec![],
+        },
+        None => None,
+    };
+    vec![duplicate_check(
+        &id,
+        &qualified_name(relation),
+        &keys,
+        predicate,
+    )]
+}
+
+/// count the groups of rows sharing the same non-NULL key
+fn duplicate_check(id: &str, table: &str, keys: &[String], predicate: Option<String>) -> Check {
+    let columns = keys.iter().map(|k| quote_ident(k)).join(", ");
+    let conditions = keys
+        .iter()
+        .map(|k| format!("{} IS NOT NULL", quote_ident(k)))
+        .chain(predicate.map(|p| format!("({})", p)))
+        .join(" AND ");
+    (
+        format!("duplicated value(s) of ({}) in {}", keys.join(", "), id),
+        PreflightQuery::Count(format!(
+            "SELECT count(*) FROM (SELECT 1 FROM {} WHERE {} GROUP BY {} HAVING count(*) > 1) d",
+            table, conditions, columns
+        )),
+    )
+}
+
+fn null_check(id: &str, table: &str, columns: &[String]) -> Check {
+    let conditions = columns
+        .iter()
+        .map(|c| format!("{} IS NULL", quote_ident(c)))
+        .join(" OR ");
+    (
+        format!("NULL value(s) in {}.({})", id, columns.join(", ")),
+        PreflightQuery::Count(format!(
+            "SELECT count(*) FROM {} WHERE {}",
+            table, conditions
+        )),
+    )
+}
+
+/// shorter `varchar(n)` / `char(n)` are checked by the length, since a cast would silently
+/// truncate them. Any other type is checked by converting the values
+fn type_check(id: &str, table: &str, column: &str, type_name: &TypeName) -> Option<Check> {
+    let base = type_name.names.last().and_then(node_to_string);
+    let typmods: Vec<_> = type_name
+        .typmods
+        .iter()
+        .filter_map(node_to_string)
+        .collect();
+    let col = quote_ident(column);
+    match (base.as_deref(), typmods.as_slice()) {
+        (Some("varchar" | "bpchar"), [len]) if type_name.array_bounds.is_empty() => Some((
+            format!("value(s) of {}.{} longer than {}", id, column, len),
+            PreflightQuery::Count(format!(
+                "SELECT count(*) FROM {} WHERE length({}) > {}",
+                table, col, len
+            )),
+        )),
+        _ => {
+            let sql = cast_query(table, &col, type_name).ok()?;
+            Some((
+                format!("value(s) of {}.{} which can't be converted", id, column),
+                PreflightQuery::Cast(sql),
+            ))
+        }
+    }
+}
+
+/// an enum type is changed by dropping and creating it again, which fails on the values in use
+/// which are removed
+fn enum_checks(stmt: &DropStmt, enums: &BTreeMap<String, Vec<String>>) -> Vec<Check> {
+    if stmt.remove_type() != ObjectType::ObjectType {
+        return vec![];
+    }
+    stmt.objects
+        .iter()
+        .filter_map(|n| match &n.node {
+            Some(NodeEnum::TypeName(t)) => Some(type_id(&t.names)),
+            Some(NodeEnum::List(l)) => Some(type_id(&l.items)),
+            _ => None,
+        })
+        .filter_map(|id| {
+            let values = enums.get(&id.to_string())?;
+            Some((
+                format!("value(s) of {} in use but not in the new enum", id),
+                PreflightQuery::EnumValues {
+                    type_name: format!("{}.{}", quote_ident(&id.schema), quote_ident(&id.name)),
+                    values: values.clone(),
+                },
+            ))
+        })
+        .collect()
+}
+
+/// the enum types created by the plan, with their values
+fn created_enums(plan: &[String]) -> Result<BTreeMap<String, Vec<String>>> {
+    let mut enums = BTreeMap::new();
+    for sql in plan {
+        if let NodeEnum::CreateEnumStmt(stmt) = parse_stmt(sql)? {
+            enums.insert(type_id(&stmt.type_name).to_string(), names(&stmt.vals));
+        }
+    }
+    Ok(enums)
+}
+
+/// the types created by the plan: enums, composite types, domains and ranges
+fn created_types(plan: &[String]) -> Result<BTreeSet<String>> {
+    let mut types = BTreeSet::new();
+    for sql in plan {
+        let id = match parse_stmt(sql)? {
+            NodeEnum::CreateEnumStmt(stmt) => type_id(&stmt.type_name),
+            NodeEnum::CreateRangeStmt(stmt) => type_id(&stmt.type_name),
+            NodeEnum::CreateDomainStmt(stmt) => type_id(&stmt.domainname),
+            NodeEnum::CompositeTypeStmt(stmt) => SchemaId::from(stmt.typevar.as_ref()),
+            _ => continue,
+        };
+        types.insert(id.to_string());
+    }
+    Ok(types)
+}
+
+/// count the rows the check expression is false for. The expression is put into the AST of
+/// the query, so that it's deparsed the way it was parsed
+fn check_query(table: &str, expr: &Node) -> Result<String> {
+    let sql = format!(
+        "SELECT count(*) FROM (SELECT true AS ok FROM {}) checked WHERE NOT checked.ok",
+        table
+    );
+    let mut stmt = match parse_stmt(&sql)? {
+        NodeEnum::SelectStmt(stmt) => stmt,
+        _ => unreachable!("should be a select statement"),
+    };
+    if let Some(NodeEnum::RangeSubselect(sub)) = stmt.from_clause[0].node.as_mut() {
+        if let Some(NodeEnum::SelectStmt(inner)) =
+            sub.subquery.as_mut().and_then(|n| n.node.as_mut())
+        {
+            if let Some(NodeEnum::ResTarget(target)) = inner.target_list[0].node.as_mut() {
+                target.val = Some(Box::new(expr.clone()));
+            }
+        }
+    }
+    Ok(NodeEnum::SelectStmt(stmt).deparse()?)
+}
+
+/// convert every value of the column to the new type, which fails on the first value that
+/// can't be converted
+fn cast_query(table: &str, column: &str, type_name: &TypeName) -> Result<String> {
+    let sql = format!(
+        "SELECT count(*) FROM {} WHERE CAST({} AS text) IS NULL AND {} IS NOT NULL",
+        table, column, column
+    );
+    let mut stmt = match parse_stmt(&sql)? {
+        NodeEnum::SelectStmt(stmt) => stmt,
+        _ => unreachable!("should be a select statement"),
+    };
+    if let Some(NodeEnum::BoolExpr(expr)) = stmt.where_clause.as_mut().and_then(|n| n.node.as_mut())
+    {
+        if let Some(NodeEnum::NullTest(test)) = expr.args[0].node.as_mut() {
+            if let Some(NodeEnum::TypeCast(cast)) = test.arg.as_mut().and_then(|n| n.node.as_mut())
+            {
+                cast.type_name = Some(type_name.clone());
+            }
+        }
+    }
+    Ok(NodeEnum::SelectStmt(stmt).deparse()?)
+}
+
+/// the `WHERE` clause of a partial index as SQL
+fn predicate_sql(expr: &Node) -> Result<String> {
+    let mut stmt = match parse_stmt("SELECT 1 WHERE true")? {
+        NodeEnum::SelectStmt(stmt) => stmt,
+        _ => unreachable!("should be a select statement"),
+    };
+    stmt.where_clause = Some(Box::new(expr.clone()));
+    let sql = NodeEnum::SelectStmt(stmt).deparse()?;
+    Ok(sql.trim_start_matches("SELECT 1 WHERE ").to_owned())
+}
+
+fn names(nodes: &[Node]) -> Vec<String> {
+    nodes.iter().filter_map(node_to_string).collect()
+}
+
+fn type_id(nodes: &[Node]) -> SchemaId {
+    let names = names(nodes);
+    let names: Vec<_> = names.iter().map(|s| s.as_str()).collect();
+    SchemaId::new_with(&names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn analyze(plan: &[&str]) -> Vec<PreflightCheck> {
+        let plan: Vec<String> = plan.iter().map(|s| s.to_string()).collect();
+        PreflightCheck::analyze_all(&plan).unwrap()
+    }
+
+    #[test]
+    fn set_not_null_should_count_nulls() {
+        let checks = analyze(&["ALTER TABLE public.todos ALTER COLUMN title SET NOT NULL"]);
+        assert_eq!(checks.len(), 1);
+        assert_eq!(checks[0].step, 0);
+        assert_eq!(
+            checks[0].description,
+            "NULL value(s) in public.todos.(title)"
+        );
+        assert_eq!(
+            checks[0].query,
+            PreflightQuery::Count(
+                r#"SELECT count(*) FROM "public"."todos" WHERE "title" IS NULL"#.to_owned()
+            )
+        );
+    }
+
+    #[test]
+    fn unique_constraint_should_count_duplicates() {
+        let checks = analyze(&[
+            "ALTER TABLE ONLY public.users ADD CONSTRAINT users_email_key UNIQUE (email)",
+        ]);
+        assert_eq!(checks.len(), 1);
+        assert_eq!(
+            checks[0].query,
+            PreflightQuery::Count(
+                r#"SELECT count(*) FROM (SELECT 1 FROM "public"."users" WHERE "email" IS NOT NULL GROUP BY "email" HAVING count(*) > 1) d"#.to_owned()
+            )
+        );
+
+        let checks = analyze(&[
+            "ALTER TABLE ONLY public.users ADD CONSTRAINT users_pkey PRIMARY KEY (id)",
+            "CREATE UNIQUE INDEX CONCURRENTLY users_name_idx ON public.users (name)",
+        ]);
+        assert_eq!(checks.len(), 3);
+        assert_eq!(checks[1].description, "NULL value(s) in public.users.(id)");
+        assert_eq!(checks[2].step, 1);
+    }
+
+    #[test]
+    fn check_and_foreign_key_should_count_violations() {
+        let checks = analyze(&[
+            "ALTER TABLE ONLY public.todos ADD CONSTRAINT todos_title_check CHECK (length(title) > 0)",
+            "ALTER TABLE ONLY public.todos ADD CONSTRAINT todos_user_id_fkey FOREIGN KEY (user_id) REFERENCES public.users(id)",
+            "ALTER TABLE ONLY public.todos ADD CONSTRAINT todos_due_check CHECK (due > now()) NOT VALID",
+        ]);
+        assert_eq!(checks.len(), 2);
+        match &checks[0].query {
+            PreflightQuery::Count(sql) => assert!(sql.contains("length(title)")),
+            q => panic!("unexpected query: {:?}", q),
+        }
+        assert_eq!(
+            checks[1].query,
+            PreflightQuery::Count(
+                r#"SELECT count(*) FROM "public"."todos" c WHERE c."user_id" IS NOT NULL AND NOT EXISTS (SELECT 1 FROM "public"."users" r WHERE r."id" = c."user_id")"#.to_owned()
+            )
+        );
+    }
+
+    #[test]
+    fn narrowing_type_should_be_checked() {
+        let checks = analyze(&[
+            "ALTER TABLE public.todos ALTER COLUMN title TYPE varchar(64)",
+            "ALTER TABLE public.todos ALTER COLUMN priority TYPE smallint",
+        ]);
+        assert_eq!(checks.len(), 2);
+        assert_eq!(
+            checks[0].query,
+            PreflightQuery::Count(
+                r#"SELECT count(*) FROM "public"."todos" WHERE length("title") > 64"#.to_owned()
+            )
+        );
+        assert!(matches!(checks[1].query, PreflightQuery::Cast(_)));
+    }
+
+    #[test]
+    fn conversion_to_created_type_should_not_be_checked() {
+        let checks = analyze(&[
+            "CREATE TYPE public.priority AS ENUM ('low', 'high')",
+            "CREATE DOMAIN public.title AS text CHECK (length(VALUE) > 0)",
+            "ALTER TABLE public.todos ALTER COLUMN priority TYPE public.priority",
+            "ALTER TABLE public.todos ALTER COLUMN title TYPE title",
+            "ALTER TABLE public.todos ALTER COLUMN done TYPE boolean",
+        ]);
+        assert_eq!(checks.len(), 1);
+        assert_eq!(checks[0].step, 4);
+    }
+
+    #[test]
+    fn only_data_errors_should_block_conversions() {
+        let checks = analyze(&["ALTER TABLE public.todos ALTER COLUMN priority TYPE smallint"]);
+        let result = |sql_state: &str| PreflightResult {
+            check: checks[0].clone(),
+            count: None,
+            error: Some("error".to_owned()),
+            sql_state: Some(sql_state.to_owned()),
+        };
+        assert!(result("22P02").blocks());
+        assert!(result("23502").blocks());
+        assert!(!result("42P01").blocks());
+        assert!(result("42P01").to_string().contains("check could not run"));
+        assert!(!result("57014").blocks());
+        assert!(result("57014").to_string().starts_with("[SKIP]"));
+        assert!(result("57014").to_string().contains("timed out"));
+    }
+
+    #[test]
+    fn recreated_enum_should_check_values_in_use() {
+        let checks = analyze(&[
+            "DROP TYPE public.status",
+            "CREATE TYPE public.status AS ENUM ('todo', 'done')",
+        ]);
+        assert_eq!(checks.len(), 1);
+        assert_eq!(
+            checks[0].query,
+            PreflightQuery::EnumValues {
+                type_name: r#""public"."status""#.to_owned(),
+                values: vec!["todo".to_owned(), "done".to_owned()],
+            }
+        );
+    }
+
+    #[test]
+    fn new_tables_and_columns_should_not_be_checked() {
+        let checks = analyze(&[
+            "CREATE TABLE public.todos (id bigint NOT NULL, title text)",
+            "ALTER TABLE ONLY public.todos ADD CONSTRAINT todos_pkey PRIMARY KEY (id)",
+            "ALTER TABLE ONLY public.users ADD COLUMN email text NOT NULL",
+            "ALTER TABLE ONLY public.users ADD CONSTRAINT users_email_key UNIQUE (email)",
+            "ALTER TABLE ONLY public.users ADD COLUMN active boolean DEFAULT true NOT NULL",
+        ]);
+        assert_eq!(checks.len(), 1);
+        assert_eq!(checks[0].step, 2);
+        assert_eq!(
+            checks[0].query,
+            PreflightQuery::Count(r#"SELECT count(*) FROM "public"."users""#.to_owned())
+        );
+    }
+}
@@ -0,0 +1,82 @@
+use super::{RiskLevel, StatementLock};
+use anyhow::Result;
+use pg_query::{
+    protobuf::{AlterTableType, ObjectType},
+    NodeEnum, NodeRef,
+};
+use std::fmt;
+
+impl RiskLevel {
+    /// assess the risk of a SQL statement based on what it does and the lock it acquires
+    pub fn assess(sql: &str, lock: &StatementLock) -> Result<Self> {
+        if loses_data(sql)? {
+            return Ok(RiskLevel::High);
+        }
+
+        let strong = matches!(lock.mode, Some(mode) if mode.is_strong());
+        if lock.rewrite || strong {
+            Ok(RiskLevel::Medium)
+        } else {
+            Ok(RiskLevel::Low)
+        }
+    }
+}
+
+impl fmt::Display for RiskLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            RiskLevel::Low => "low",
+            RiskLevel::Medium => "medium",
+            RiskLevel::High => "high",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// dropping tables, sequences or columns can't be undone without losing data
+fn loses_data(sql: &str) -> Result<bool> {
+    let parsed = pg_query::parse(sql)?;
+    let lost = match parsed.protobuf.nodes().first().map(|(node, _, _)| *node) {
+        Some(NodeRef::DropStmt(stmt)) => matches!(
+            stmt.remove_type(),
+            ObjectType::ObjectTable | ObjectType::ObjectSequence
+        ),
+        Some(NodeRef::AlterTableStmt(stmt)) => stmt.cmds.iter().any(|n| {
+            matches!(&n.node, Some(NodeEnum::AlterTableCmd(cmd)) if cmd.subtype() == AlterTableType::AtDropColumn)
+        }),
+        _ => false,
+    };
+    Ok(lost)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assess(sql: &str) -> RiskLevel {
+        let lock = StatementLock::analyze(sql).unwrap();
+        RiskLevel::assess(sql, &lock).unwrap()
+    }
+
+    #[test]
+    fn dropping_data_should_be_high_risk() {
+        assert_eq!(assess("DROP TABLE public.todos"), RiskLevel::High);
+        assert_eq!(
+            assess("ALTER TABLE public.todos DROP COLUMN title"),
+            RiskLevel::High
+        );
+    }
+
+    #[test]
+    fn strong_lock_should_be_medium_risk() {
+        assert_eq!(
+            assess("ALTER TABLE ONLY public.todos ADD COLUMN created_at timestamptz"),
+            RiskLevel::Medium
+        );
+        assert_eq!(
+            assess("CREATE INDEX CONCURRENTLY todos_title_idx ON todos (title)"),
+            RiskLevel::Low
+        );
+        assert_eq!(assess("CREATE TABLE public.foo (id int)"), RiskLevel::Low);
+    }
+}
@@ -0,0 +1,30 @@
+use anyhow::Result;
+use pg_query::NodeRef;
+
+/// check if the SQL statement could run inside a transaction block
+pub(crate) fn is_transactional(sql: &str) -> Result<bool> {
+    let parsed = pg_query::parse(sql)?;
+    let transactional = match parsed.protobuf.nodes().first().map(|(node, _, _)| *node) {
+        Some(NodeRef::IndexStmt(stmt)) => !stmt.concurrent,
+        Some(NodeRef::DropStmt(stmt)) => !stmt.concurrent,
+        // a new enum value can't be used until the transaction which adds it is committed
+        Some(NodeRef::AlterEnumStmt(stmt)) => !stmt.old_val.is_empty(),
+        Some(NodeRef::VacuumStmt(_)) => false,
+        _ => true,
+    };
+    Ok(transactional)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn concurrent_index_should_not_be_transactional() {
+        let sql = "CREATE INDEX CONCURRENTLY todos_title_idx ON todos (title)";
+        assert!(!is_transactional(sql).unwrap());
+        assert!(is_transactional("CREATE INDEX todos_title_idx ON todos (title)").unwrap());
+        assert!(!is_transactional("ALTER TYPE public.status ADD VALUE 'done'").unwrap());
+        assert!(is_transactional("ALTER TYPE public.status RENAME VALUE 'a' TO 'b'").unwrap());
+    }
+}
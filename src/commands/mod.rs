@@ -5,6 +5,8 @@ use clap_utils::prelude::*;
 use generate::*;
 use schema::*;
 
+pub use schema::DriftDetected;
+
 /// Dispatch and execute the command. Make sure to add the new command enum into the enum_dispatch macro below.
 #[async_trait]
 #[enum_dispatch(Action, Generate, Schema)] // <- [new group] put the new group enum here
@@ -20,12 +22,34 @@ pub struct Args {
     #[clap(subcommand)]
     pub action: Action,
 
+    /// answer yes to every prompt, e.g. to apply a plan in CI
+    #[clap(short, long, global = true, value_parser, default_value = "false")]
+    pub yes: bool,
+
+    /// never prompt, and fail where a confirmation is needed
+    #[clap(long, global = true, value_parser, default_value = "false")]
+    pub no_input: bool,
+
     #[cfg(feature = "cli-test")]
     /// drop database on exit (for testing purpose only)
     #[clap(long, global = true, value_parser, default_value = "false")]
     pub drop_on_exit: bool,
 }
 
+/// env var to never prompt, same as `--no-input`
+const NON_INTERACTIVE_ENV: &str = "RENOVATE_NON_INTERACTIVE";
+
+impl Args {
+    /// prompts can't be answered with `--no-input`, `RENOVATE_NON_INTERACTIVE` or without a
+    /// terminal, e.g. in CI
+    pub fn non_interactive(&self) -> bool {
+        let env = std::env::var(NON_INTERACTIVE_ENV)
+            .map(|v| !matches!(v.as_str(), "" | "0" | "false"))
+            .unwrap_or(false);
+        self.no_input || env || !atty::is(atty::Stream::Stdin)
+    }
+}
+
 subcmd!(
     Action,
     // [new group] add the new command enum here
@@ -1,46 +1,242 @@
-use super::{generate_plan, git_commit, git_dirty, Args, CommandExecutor};
-use crate::{utils::load_config, DatabaseRepo};
+use super::{
+    generate_plan, git_commit, git_commit_id, git_dirty, load_desired_schema, load_target_schema,
+    print_plan, verify_plan, Args, CommandExecutor, PlanFormat,
+};
+use crate::{
+    config::parse_duration, connection::mask_url, utils::load_config, ApplyReport, DatabaseRepo,
+    EnvironmentState, MigrationPhase, RenovateApplyConfig, SavedPlan,
+};
 use clap_utils::{
     dialoguer::{theme::ColorfulTheme, Confirm},
     prelude::*,
 };
+use std::path::PathBuf;
 
 #[derive(Parser, Debug, Clone)]
 pub struct SchemaApplyCommand {
+    /// the plan file saved by `renovate schema plan --out`. A new plan is made if not provided
+    #[clap(value_parser)]
+    plan: Option<PathBuf>,
     #[clap(long, value_parser, default_value = "false")]
     remote: bool,
+    /// apply to the environment in renovate.yml. Default to the one the plan file was made for
+    #[clap(long, value_parser, conflicts_with = "remote")]
+    env: Option<String>,
+    /// max time a statement waits for a lock, e.g. `5s`. Overrides `apply.lock_timeout`
+    #[clap(long, value_parser = parse_timeout)]
+    lock_timeout: Option<String>,
+    /// max time a statement runs, e.g. `10min`. Overrides `apply.statement_timeout`
+    #[clap(long, value_parser = parse_timeout)]
+    statement_timeout: Option<String>,
+    /// how many times to retry a batch after a lock timeout. Overrides `apply.retries`
+    #[clap(long, value_parser)]
+    retries: Option<u32>,
+    /// max time to wait for another apply on the same database to finish, e.g. `1min`.
+    /// Overrides `apply.apply_lock_timeout`
+    #[clap(long, value_parser = parse_timeout)]
+    apply_lock_timeout: Option<String>,
+    /// write a json report of the applied statements to the file, e.g. for CI to archive
+    #[clap(long, value_parser)]
+    report: Option<PathBuf>,
+    /// apply the plan to a shadow clone of the target database first, and only apply it for
+    /// real if the result matches the local schema
+    #[clap(long, value_parser, default_value = "false")]
+    verify: bool,
+    /// don't run the read-only queries which count the existing rows risky steps would fail on,
+    /// and apply even if they would find some
+    #[clap(long, value_parser, default_value = "false")]
+    skip_preflight: bool,
+    /// apply only the steps of the phase: `pre` before deploying the app, `post` after it
+    #[clap(long, value_parser, conflicts_with = "verify")]
+    phase: Option<MigrationPhase>,
 }
 
 #[async_trait]
 impl CommandExecutor for SchemaApplyCommand {
-    async fn execute(&self, _args: &Args) -> Result<(), Error> {
-        let plan = generate_plan(self.remote).await?;
-        if plan.is_empty() {
+    async fn execute(&self, args: &Args) -> Result<(), Error> {
+        let mut config = load_config().await?;
+        self.override_apply_config(&mut config.apply);
+
+        // the plan file tells the target, so it's read before taking the lock
+        let planned = match &self.plan {
+            Some(path) => Some(SavedPlan::load(path).await?),
+            None => None,
+        };
+        let env = match (&self.env, planned.as_ref().and_then(|s| s.env.as_ref())) {
+            (Some(env), Some(planned)) if env != planned => bail!(
+                "The plan was made for environment {}, not {}.",
+                planned,
+                env
+            ),
+            (env, planned) => env.clone().or_else(|| planned.cloned()),
+        };
+        let target_config = match &env {
+            Some(name) => config.with_env(name)?,
+            None => config.clone(),
+        };
+        let remote = match &planned {
+            Some(saved) => saved.remote,
+            None => self.remote || env.is_some(),
+        };
+        let db_repo = DatabaseRepo::new(&target_config);
+
+        // the fingerprint of the target the plan is checked against is kept, to check it again
+        // once the apply lock is taken
+        let (saved, fingerprint) = match planned {
+            Some(mut saved) => {
+                let target = load_target_schema(&db_repo, saved.remote).await?;
+                match self.phase {
+                    Some(MigrationPhase::Post) if saved.plan.has_phase(MigrationPhase::Pre) => {
+                        let history = db_repo.history(saved.remote).await?;
+                        saved.check_pre_applied(&history, &target)?;
+                    }
+                    _ => saved.check_drift(&target)?,
+                }
+                if let Some(phase) = self.phase {
+                    saved = saved.for_phase(phase);
+                }
+                // the data may have changed since the plan was made
+                saved.preflight = if self.skip_preflight {
+                    vec![]
+                } else {
+                    db_repo.preflight(&saved).await?
+                };
+                print_plan(&saved, &target_config, PlanFormat::Text)?;
+                if self.verify && !saved.plan.is_empty() {
+                    let expected = load_desired_schema(
+                        &db_repo,
+                        &target_config,
+                        saved.remote,
+                        env.as_deref(),
+                        false,
+                    )
+                    .await?;
+                    verify_plan(&db_repo, &saved, &expected).await?;
+                }
+                (saved, target.fingerprint())
+            }
+            None => {
+                let format = PlanFormat::Text;
+                let preflight = !self.skip_preflight;
+                let saved = generate_plan(
+                    &config,
+                    self.remote,
+                    false,
+                    env.as_deref(),
+                    format,
+                    self.verify,
+                    preflight,
+                )
+                .await?;
+                let fingerprint = saved.fingerprint.clone();
+                let saved = match self.phase {
+                    Some(phase) => {
+                        if phase == MigrationPhase::Post
+                            && saved.plan.has_phase(MigrationPhase::Pre)
+                        {
+                            bail!("The plan still has pre-deploy steps. Run `renovate schema apply --phase pre` first.");
+                        }
+                        saved.for_phase(phase)
+                    }
+                    None => saved,
+                };
+                (saved, fingerprint)
+            }
+        };
+        if saved.plan.is_empty() {
+            if let Some(phase) = self.phase {
+                println!("No {}-deploy steps in the plan.", phase);
+            }
             return Ok(());
         }
-        let config = load_config().await?;
-        let db_repo = DatabaseRepo::new(&config);
+        if let Some(phase) = self.phase {
+            println!("\nApplying the {}-deploy phase of the plan.", phase);
+        }
+        let failures = saved.preflight_failures();
+        if failures > 0 {
+            bail!(
+                "{} preflight check(s) found existing rows the plan would fail on. Fix the data first, or pass --skip-preflight to apply anyway.",
+                failures
+            );
+        }
+
+        let env_config = env.as_deref().map(|e| config.environment(e)).transpose()?;
+        let risk = saved.plan.risk();
+        if let (Some(name), Some(env_config)) = (&env, env_config) {
+            if risk > env_config.max_risk {
+                bail!(
+                    "The plan has {} risk steps, but environment {} only allows up to {} risk.",
+                    risk,
+                    name,
+                    env_config.max_risk
+                );
+            }
+        }
 
         if git_dirty()? {
-            if confirm("\nYour repo is dirty. Do you want to commit it first?") {
+            if confirm(
+                args,
+                "\nYour repo is dirty. Do you want to commit it first?",
+            )? {
                 git_commit("automatically commit the schema changes before applying the plan")?;
             } else {
                 bail!("Your repo is dirty. Please commit the changes before applying.");
             }
         }
 
-        if confirm("Do you want to perform this update?") {
-            db_repo.apply(plan, self.remote).await?;
-            git_commit("automatically commit the changes applied to remote server")?;
-            let url = if self.remote {
-                &config.remote_url
-            } else {
-                &config.url
-            };
+        let should_confirm = env_config.map_or(true, |e| e.confirm.should_confirm(risk));
+        if let (false, Some(name)) = (should_confirm, &env) {
             println!(
-                "Successfully applied migration to {}.\nYour repo is updated with the latest schema. See `git diff HEAD~1` for details.",
-                url
+                "Applying without confirmation, as allowed by the confirm policy of environment {}.",
+                name
             );
+        }
+        if !should_confirm || confirm(args, "Do you want to perform this update?")? {
+            // taken after the prompts, so that a prompt left open never blocks other applies.
+            // An apply which waited for another one fails if that one changed the target. The
+            // lock is released when dropped, e.g. on an error
+            let lock = db_repo.lock_apply(remote).await?;
+            let target = load_target_schema(&db_repo, remote).await?;
+            if target.fingerprint() != fingerprint {
+                bail!("The target database has changed since the plan was made, e.g. by another apply. Please make a new plan.");
+            }
+
+            let commit_id = git_commit_id();
+            let mut report = ApplyReport::new(&saved.plan, "");
+            report.env = env.clone();
+            let ret = db_repo
+                .apply_with_report(&saved, commit_id.as_deref(), lock, &mut report)
+                .await;
+            // the report is the most useful when the plan failed
+            if let Some(path) = &self.report {
+                report.save(path).await?;
+            }
+            ret?;
+
+            match &env {
+                Some(name) => {
+                    EnvironmentState::new(commit_id, saved.plan.id())
+                        .save(&config.output.path, name)
+                        .await?;
+                    git_commit(format!(
+                        "automatically commit the state of environment {}",
+                        name
+                    ))?;
+                    println!("Successfully applied migration to environment {}.", name);
+                }
+                None => {
+                    git_commit("automatically commit the changes applied to remote server")?;
+                    let url = if saved.remote {
+                        &config.remote_url
+                    } else {
+                        &config.url
+                    };
+                    println!(
+                        "Successfully applied migration to {}.\nYour repo is updated with the latest schema. See `git diff HEAD~1` for details.",
+                        mask_url(url)
+                    );
+                }
+            }
         } else {
             println!("Database schema update has been cancelled.");
         }
@@ -49,9 +245,42 @@ impl CommandExecutor for SchemaApplyCommand {
     }
 }
 
-pub(crate) fn confirm(prompt: &'static str) -> bool {
-    Confirm::with_theme(&ColorfulTheme::default())
+impl SchemaApplyCommand {
+    fn override_apply_config(&self, apply: &mut RenovateApplyConfig) {
+        if let Some(timeout) = &self.lock_timeout {
+            apply.lock_timeout = Some(timeout.clone());
+        }
+        if let Some(timeout) = &self.statement_timeout {
+            apply.statement_timeout = Some(timeout.clone());
+        }
+        if let Some(retries) = self.retries {
+            apply.retries = retries;
+        }
+        if let Some(timeout) = &self.apply_lock_timeout {
+            apply.apply_lock_timeout = Some(timeout.clone());
+        }
+    }
+}
+
+fn parse_timeout(s: &str) -> Result<String, Error> {
+    parse_duration(s)?;
+    Ok(s.to_owned())
+}
+
+/// ask the user to confirm. With `--yes` it's confirmed, and in non-interactive mode it fails
+/// instead of prompting. The assumed answer is printed, so that CI logs show the decision
+pub(crate) fn confirm(args: &Args, prompt: &str) -> Result<bool> {
+    if args.yes {
+        println!("{} yes (--yes)", prompt);
+        return Ok(true);
+    }
+    if args.non_interactive() {
+        bail!(
+            "{} Can't ask for confirmation in non-interactive mode. Pass --yes to confirm.",
+            prompt.trim()
+        );
+    }
+    Ok(Confirm::with_theme(&ColorfulTheme::default())
         .with_prompt(prompt)
-        .interact()
-        .expect("confirm UI should work")
+        .interact()?)
 }
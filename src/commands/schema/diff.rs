@@ -0,0 +1,44 @@
+use super::{print_plan, Args, CommandExecutor, PlanFormat};
+use crate::{RenovateConfig, SavedPlan, SchemaSource};
+use clap_utils::prelude::*;
+use std::path::Path;
+
+#[derive(Parser, Debug, Clone)]
+pub struct SchemaDiffCommand {
+    /// the source to migrate from: a postgres url, a repo directory, a sql file or git:<ref>
+    #[clap(value_parser)]
+    from: SchemaSource,
+    /// the source to migrate to: a postgres url, a repo directory, a sql file or git:<ref>
+    #[clap(value_parser)]
+    to: SchemaSource,
+    /// output format of the plan
+    #[clap(long, value_enum, default_value = "text")]
+    format: PlanFormat,
+}
+
+#[async_trait]
+impl CommandExecutor for SchemaDiffCommand {
+    async fn execute(&self, _args: &Args) -> Result<(), Error> {
+        // diff could be used outside of a renovate repo, fallback to the default formatting
+        let config_file = Path::new("renovate.yml");
+        let config = if config_file.exists() {
+            RenovateConfig::load(config_file)
+                .await?
+                .with_shadow_url_env()
+        } else {
+            RenovateConfig::default()
+        };
+
+        let filter = config.filter();
+        let mut from = self.from.load(&config).await?;
+        let mut to = self.to.load(&config).await?;
+        filter.apply(&mut from);
+        filter.apply(&mut to);
+        let plan = to.plan(&from)?;
+        let mut rollback = from.rollback(&to)?;
+        rollback.flag_lossy(&plan);
+
+        let saved = SavedPlan::new(plan, rollback, &from, false);
+        print_plan(&saved, &config, self.format)
+    }
+}
@@ -0,0 +1,52 @@
+use super::{Args, CommandExecutor};
+use crate::{pg_dump_version, utils::load_config, DatabaseRepo};
+use clap_utils::prelude::*;
+use std::future::Future;
+
+#[derive(Parser, Debug, Clone)]
+pub struct SchemaDoctorCommand {}
+
+#[async_trait]
+impl CommandExecutor for SchemaDoctorCommand {
+    async fn execute(&self, _args: &Args) -> Result<(), Error> {
+        let config = load_config().await?;
+        let db_repo = DatabaseRepo::new(&config);
+
+        let mut failed = 0;
+        failed += check("pg_dump", pg_dump_version()).await;
+        failed += check("local database (url)", async {
+            db_repo.check_connection(false).await?;
+            Ok("connected".to_owned())
+        })
+        .await;
+        failed += check("remote database (remote_url)", async {
+            db_repo.check_connection(true).await?;
+            Ok("connected".to_owned())
+        })
+        .await;
+        failed += check("shadow database server", async {
+            db_repo.check_shadow().await?;
+            Ok("temp database created and dropped".to_owned())
+        })
+        .await;
+
+        if failed > 0 {
+            bail!("{} check(s) failed", failed);
+        }
+        Ok(())
+    }
+}
+
+/// print the result of the check, and return 1 if it failed
+async fn check(name: &str, f: impl Future<Output = Result<String>>) -> usize {
+    match f.await {
+        Ok(msg) => {
+            println!("[ok] {}: {}", name, msg);
+            0
+        }
+        Err(e) => {
+            println!("[failed] {}: {}\n", name, e);
+            1
+        }
+    }
+}
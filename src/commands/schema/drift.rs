@@ -0,0 +1,137 @@
+use super::{Args, CommandExecutor};
+use crate::{
+    utils::load_config, DatabaseRepo, GitRevision, MigrationAction, Normalizer, SchemaLoader,
+    SqlLoader,
+};
+use clap::ValueEnum;
+use clap_utils::prelude::*;
+use serde::Serialize;
+use std::{collections::BTreeMap, fmt};
+
+/// Returned by `schema drift` when the remote database drifted from the local repo. The drift
+/// is printed already, so `main` only exits with `EXIT_CODE`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DriftDetected;
+
+impl DriftDetected {
+    pub const EXIT_CODE: u8 = 2;
+}
+
+impl fmt::Display for DriftDetected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The remote database has drifted from the local repo")
+    }
+}
+
+impl std::error::Error for DriftDetected {}
+
+#[derive(Parser, Debug, Clone)]
+pub struct SchemaDriftCommand {
+    /// output format of the drifted objects
+    #[clap(long, value_enum, default_value = "text")]
+    format: DriftFormat,
+    /// normalize the local repo before comparing. Only needed if the local files are not in the
+    /// form of `pg_dump`, e.g. edited by hand
+    #[clap(long, value_parser, default_value = "false")]
+    normalize: bool,
+    /// check the environment in renovate.yml instead of the remote database
+    #[clap(long, value_parser)]
+    env: Option<String>,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DriftFormat {
+    Text,
+    Json,
+}
+
+/// An object which is changed in the remote database but not in the local repo
+#[derive(Serialize, Debug)]
+struct DriftedObject<'a> {
+    #[serde(rename = "type")]
+    type_name: &'a str,
+    id: &'a str,
+    change: &'static str,
+    diff: &'a str,
+}
+
+#[async_trait]
+impl CommandExecutor for SchemaDriftCommand {
+    async fn execute(&self, _args: &Args) -> Result<(), Error> {
+        let config = load_config().await?;
+        let (config, roles) = match &self.env {
+            Some(name) => (
+                config.with_env(name)?,
+                config.environment(name)?.roles.clone(),
+            ),
+            None => (config, Default::default()),
+        };
+        let db_repo = DatabaseRepo::new(&config);
+        // the committed schema, so that uncommitted edits don't hide or fake a drift
+        let sql = GitRevision::new(&config.output.path, "HEAD")
+            .load_sql()
+            .await?;
+
+        let sql = if self.normalize {
+            Normalizer::with_url(&config.remote_url)
+                .with_roles(roles)
+                .normalize(&sql)?
+        } else {
+            Normalizer::default().with_roles(roles).rename_roles(&sql)?
+        };
+        let mut local = SqlLoader::new(sql).load().await?;
+        config.filter().apply(&mut local);
+        let remote = db_repo.load_schema(true).await?;
+
+        // the plan to bring the local repo to the remote state tells what was changed remotely
+        let plan = remote.plan(&local)?;
+        let mut groups: BTreeMap<&str, Vec<DriftedObject>> = BTreeMap::new();
+        for object in plan.objects() {
+            let change = match object.action() {
+                MigrationAction::Create => "added",
+                MigrationAction::Alter => "changed",
+                MigrationAction::Drop => "removed",
+            };
+            groups
+                .entry(object.type_name)
+                .or_default()
+                .push(DriftedObject {
+                    type_name: object.type_name,
+                    id: object.id,
+                    change,
+                    diff: object.diff(),
+                });
+        }
+
+        match self.format {
+            DriftFormat::Json => {
+                let objects: Vec<_> = groups.values().flatten().collect();
+                let output =
+                    serde_json::json!({ "drift": !objects.is_empty(), "objects": objects });
+                println!("{}", serde_json::to_string_pretty(&output)?);
+            }
+            DriftFormat::Text if groups.is_empty() => {
+                println!("No drift detected.");
+            }
+            DriftFormat::Text => {
+                println!("The remote database has drifted from the local repo:\n");
+                for (type_name, objects) in &groups {
+                    println!("{}:", type_name);
+                    for object in objects {
+                        let sign = match object.change {
+                            "added" => "+",
+                            "removed" => "-",
+                            _ => "~",
+                        };
+                        println!("  {} {} ({})", sign, object.id, object.change);
+                    }
+                }
+            }
+        }
+
+        if !groups.is_empty() {
+            return Err(DriftDetected.into());
+        }
+        Ok(())
+    }
+}
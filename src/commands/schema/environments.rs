@@ -0,0 +1,44 @@
+use super::{Args, CommandExecutor};
+use crate::{utils::load_config, EnvironmentState};
+use clap_utils::prelude::*;
+
+#[derive(Parser, Debug, Clone)]
+pub struct SchemaEnvironmentsCommand {}
+
+#[async_trait]
+impl CommandExecutor for SchemaEnvironmentsCommand {
+    async fn execute(&self, _args: &Args) -> Result<(), Error> {
+        let config = load_config().await?;
+        if config.environments.is_empty() {
+            println!("No environments are defined in renovate.yml.");
+            return Ok(());
+        }
+
+        let states = EnvironmentState::load_all(&config.output.path).await?;
+        // the environment applied most recently is the one others are compared with
+        let latest = states
+            .iter()
+            .filter(|(name, _)| config.environments.contains_key(*name))
+            .max_by(|(_, a), (_, b)| a.applied_at.cmp(&b.applied_at));
+
+        for name in config.environments.keys() {
+            match states.get(name) {
+                Some(state) => {
+                    let commit = state.commit_id.as_deref().unwrap_or("-");
+                    let behind = match latest {
+                        Some((latest, s)) if latest != name && s.commit_id != state.commit_id => {
+                            format!(" (behind {})", latest)
+                        }
+                        _ => "".to_owned(),
+                    };
+                    println!(
+                        "{}: commit {}, plan {}, applied at {}{}",
+                        name, commit, state.plan_id, state.applied_at, behind
+                    );
+                }
+                None => println!("{}: never applied", name),
+            }
+        }
+        Ok(())
+    }
+}
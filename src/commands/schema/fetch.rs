@@ -1,19 +1,44 @@
 use super::{confirm, git_commit, Args, CommandExecutor};
-use crate::{utils::load_config, DatabaseRepo};
+use crate::{utils::load_config, DatabaseRepo, Normalizer, SchemaLoader, SqlLoader, SqlSaver};
 use clap_utils::prelude::*;
 
 #[derive(Parser, Debug, Clone)]
-pub struct SchemaFetchCommand {}
+pub struct SchemaFetchCommand {
+    /// fetch from the environment in renovate.yml instead of the local database
+    #[clap(long, value_parser)]
+    env: Option<String>,
+}
 
 #[async_trait]
 impl CommandExecutor for SchemaFetchCommand {
-    async fn execute(&self, _args: &Args) -> Result<(), Error> {
+    async fn execute(&self, args: &Args) -> Result<(), Error> {
         let config = load_config().await?;
-        let repo = DatabaseRepo::new(&config);
 
-        if confirm("This will overwrite the local schema files. Continue?") {
+        if confirm(
+            args,
+            "This will overwrite the local schema files. Continue?",
+        )? {
             git_commit("commit schema changes before fetching")?;
-            repo.fetch().await?;
+            match &self.env {
+                Some(name) => {
+                    // map the roles of the environment back to the local ones
+                    let roles = config
+                        .environment(name)?
+                        .roles
+                        .iter()
+                        .map(|(local, env)| (env.clone(), local.clone()))
+                        .collect();
+                    let repo = DatabaseRepo::new(&config.with_env(name)?);
+                    let sql = repo.load_sql_string(true).await?;
+                    let sql = Normalizer::default().with_roles(roles).rename_roles(&sql)?;
+                    let mut schema = SqlLoader::new(sql).load().await?;
+                    config.filter().apply(&mut schema);
+                    schema.save(&config.output).await?;
+                }
+                None => {
+                    DatabaseRepo::new(&config).fetch().await?;
+                }
+            }
         }
         Ok(())
     }
@@ -0,0 +1,50 @@
+use super::{Args, CommandExecutor};
+use crate::{utils::load_config, LocalRepo};
+use clap_utils::prelude::*;
+
+#[derive(Parser, Debug, Clone)]
+pub struct SchemaFormatCommand {
+    /// don't write the files, but fail if any of them is not formatted, e.g. in CI
+    #[clap(long, value_parser, default_value = "false")]
+    check: bool,
+}
+
+#[async_trait]
+impl CommandExecutor for SchemaFormatCommand {
+    async fn execute(&self, _args: &Args) -> Result<(), Error> {
+        let config = load_config().await?;
+        let local_repo = LocalRepo::new(&config.output.path);
+        let ret = local_repo.format(config.output.format, self.check).await?;
+        let files = ret.changed;
+
+        if self.check {
+            for file in &files {
+                println!("{} is not formatted.", file.display());
+            }
+            for file in &ret.commented {
+                println!(
+                    "{} is not formatted, and has comments, so it has to be fixed by hand.",
+                    file.display()
+                );
+            }
+            let count = files.len() + ret.commented.len();
+            if count > 0 {
+                bail!(
+                    "{} file(s) are not formatted. Run `renovate schema format` to fix them.",
+                    count
+                );
+            }
+        } else {
+            for file in &ret.commented {
+                eprintln!(
+                    "{} is skipped, since formatting would drop its comments. Fix it by hand.",
+                    file.display()
+                );
+            }
+            for file in &files {
+                println!("{} is formatted.", file.display());
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,51 @@
+use super::{Args, CommandExecutor};
+use crate::{utils::load_config, DatabaseRepo};
+use clap_utils::prelude::*;
+
+#[derive(Parser, Debug, Clone)]
+pub struct SchemaHistoryCommand {
+    /// list the migrations applied to the remote database server instead of the local one
+    #[clap(long, value_parser, default_value = "false")]
+    remote: bool,
+    /// show the statements of each migration
+    #[clap(long, short, value_parser, default_value = "false")]
+    verbose: bool,
+}
+
+#[async_trait]
+impl CommandExecutor for SchemaHistoryCommand {
+    async fn execute(&self, _args: &Args) -> Result<(), Error> {
+        let config = load_config().await?;
+        let repo = DatabaseRepo::new(&config);
+
+        let records = repo.history(self.remote).await?;
+        if records.is_empty() {
+            println!("No migrations have been applied yet.");
+            return Ok(());
+        }
+
+        for record in records {
+            println!(
+                "#{} plan {}{} applied at {} by {} from commit {} in {}ms (schema {})",
+                record.id,
+                record.plan_id,
+                record
+                    .phase
+                    .as_deref()
+                    .map(|phase| format!(" ({}-deploy phase)", phase))
+                    .unwrap_or_default(),
+                record.applied_at,
+                record.applied_by,
+                record.commit_id.as_deref().unwrap_or("-"),
+                record.duration_ms,
+                record.fingerprint.get(..12).unwrap_or(&record.fingerprint),
+            );
+            if self.verbose {
+                for sql in &record.statements {
+                    println!("    {};", sql);
+                }
+            }
+        }
+        Ok(())
+    }
+}
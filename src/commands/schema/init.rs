@@ -1,5 +1,5 @@
 use super::{git_commit, Args, CommandExecutor};
-use crate::{DatabaseRepo, RenovateConfig};
+use crate::{connection::mask_url, DatabaseRepo, RenovateConfig};
 use clap_utils::prelude::*;
 use std::{env::set_current_dir, fs, path::PathBuf};
 use url::Url;
@@ -21,21 +21,18 @@ impl CommandExecutor for SchemaInitCommand {
         }
 
         set_current_dir(&path)?;
-        let config = RenovateConfig::new(self.url.clone());
-        config.save("renovate.yml").await?;
-
-        let db_repo = DatabaseRepo::new(&config);
-        db_repo.init_local_database().await?;
-
-        db_repo.fetch().await?;
-
-        git_commit(format!("init schema migration repo for {}", self.url))?;
+        fetch_and_save(&self.url).await?;
 
         println!(
             "Database schema for {} has successfully dumped into {}.",
-            self.url,
+            mask_url(self.url.as_str()),
             path.display()
         );
+        if self.url.password().is_some() {
+            println!(
+                "The password is not stored in renovate.yml. Provide it by PGPASSWORD, ~/.pgpass, or a ${{VAR}} in the url."
+            );
+        }
         Ok(())
     }
 }
@@ -51,16 +48,19 @@ fn parse_url(s: &str) -> Result<Url, Error> {
     Ok(url)
 }
 
-#[allow(dead_code)]
 async fn fetch_and_save(url: &Url) -> Result<()> {
+    // the repo is committed to git, so only a password-free url template is stored
     let config = RenovateConfig::new(url.clone());
-    config.save("renovate.yml").await?;
+    config.without_passwords().save("renovate.yml").await?;
 
-    let db_repo = DatabaseRepo::new(&config);
+    let db_repo = DatabaseRepo::new(&config.with_shadow_url_env());
     db_repo.init_local_database().await?;
 
     db_repo.fetch().await?;
 
-    git_commit(format!("init schema migration repo for {}", url))?;
+    git_commit(format!(
+        "init schema migration repo for {}",
+        mask_url(url.as_str())
+    ))?;
     Ok(())
 }
@@ -1,4 +1,17 @@
-mod_pub_use!(apply, fetch, init, normalize, plan);
+mod_pub_use!(
+    apply,
+    diff,
+    doctor,
+    drift,
+    environments,
+    fetch,
+    format,
+    history,
+    init,
+    normalize,
+    plan,
+    rollback
+);
 
 use super::{Args, CommandExecutor};
 use clap_utils::prelude::*;
@@ -20,10 +33,17 @@ subcmd!(
     Schema,
     [
         Apply = "apply the migration plan to the remote database server",
+        Diff = "diff two schemas from database urls, directories, sql files or git refs",
+        Doctor = "check the tools and database servers renovate depends on",
+        Drift = "check if the remote database has drifted from the local repo",
+        Environments = "show the migration last applied to each environment",
         Fetch = "fetch the most recent schema from the remote database server",
+        Format = "rewrite the local sql files into the form of pg_dump",
+        History = "list the migrations applied to the database server",
         Init = "init a database migration repo",
         Normalize = "normalize local schema via a temp local database",
-        Plan = "diff the local change and remote state, then make a migration plan"
+        Plan = "diff the local change and remote state, then make a migration plan",
+        Rollback = "roll back the last migration applied to the database server"
     ]
 );
 
@@ -56,3 +76,15 @@ fn git_dirty() -> Result<bool> {
     let repo = crate::GitRepo::open(".")?;
     Ok(repo.is_dirty())
 }
+
+#[cfg(feature = "cli-test")]
+fn git_commit_id() -> Option<String> {
+    None
+}
+
+#[cfg(not(feature = "cli-test"))]
+fn git_commit_id() -> Option<String> {
+    crate::GitRepo::open(".")
+        .and_then(|repo| repo.get_last_commit_id())
+        .ok()
+}
@@ -1,53 +1,473 @@
 use super::{Args, CommandExecutor};
-use crate::{utils::load_config, DatabaseRepo, LocalRepo, SchemaLoader, SqlLoader};
+use crate::{
+    utils::{colorize_diff, load_config},
+    DatabaseRepo, DatabaseSchema, GitRevision, LocalRepo, MigrationAction, MigrationHook,
+    MigrationPhase, MigrationPlan, MigrationStyle, Normalizer, RenovateConfig, SavedPlan,
+    SchemaLoader, SqlLoader,
+};
+use clap::ValueEnum;
 use clap_utils::{highlight_text, prelude::*};
+use std::path::PathBuf;
 
 #[derive(Parser, Debug, Clone)]
-pub struct SchemaPlanCommand {}
+pub struct SchemaPlanCommand {
+    /// fail if the plan takes a strong lock on any of the `hot_tables` in renovate.yml
+    #[clap(long, value_parser, default_value = "false")]
+    fail_on_hot_locks: bool,
+    /// output format of the plan
+    #[clap(long, value_enum, default_value = "text")]
+    format: PlanFormat,
+    /// save the plan to the file, to be applied later by `renovate schema apply <file>`
+    #[clap(long, value_parser)]
+    out: Option<PathBuf>,
+    /// write the plan as versioned migration files to the directory
+    #[clap(long, value_parser)]
+    emit_migration: Option<PathBuf>,
+    /// style of the emitted migration files: sqlx, flyway, refinery or dbmate
+    #[clap(long, value_parser, default_value = "sqlx")]
+    style: MigrationStyle,
+    /// name of the emitted migration, put in the file names after the version. Default to
+    /// `renovate`
+    #[clap(long, value_parser = parse_migration_name, requires = "emit_migration")]
+    name: Option<String>,
+    /// make the plan offline from the local repo at the git revision, without any database
+    #[clap(long, value_parser)]
+    from: Option<String>,
+    /// the git revision to plan to, used with `--from`. Default to the working tree
+    #[clap(long, value_parser, requires = "from")]
+    to: Option<String>,
+    /// normalize the local schema via a temp database, and warn on what the built-in rules miss
+    #[clap(long, value_parser, default_value = "false")]
+    normalize_with_db: bool,
+    /// plan against the environment in renovate.yml instead of the local database
+    #[clap(long, value_parser, conflicts_with = "from")]
+    env: Option<String>,
+    /// apply the plan to a shadow clone of the target database, and check that the result
+    /// matches the local schema
+    #[clap(long, value_parser, default_value = "false", conflicts_with = "from")]
+    verify: bool,
+    /// don't run the read-only queries which count the existing rows risky steps would fail on
+    #[clap(long, value_parser, default_value = "false")]
+    skip_preflight: bool,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlanFormat {
+    /// human readable output. Object diffs are shown when stdout is a terminal
+    Text,
+    /// every object diff and statement of the plan as JSON
+    Json,
+    /// a report with a summary table and collapsible object diffs, e.g. for PR comments
+    Markdown,
+    /// the SQL statements as a script, annotated with `--` comments for the locks, warnings,
+    /// hooks and batches
+    Sql,
+}
 
 #[async_trait]
 impl CommandExecutor for SchemaPlanCommand {
     async fn execute(&self, _args: &Args) -> Result<(), Error> {
-        generate_plan(false).await?;
+        let config = load_config().await?;
+        let saved = match &self.from {
+            Some(from) => {
+                generate_offline_plan(&config, from, self.to.as_deref(), self.format).await?
+            }
+            None => {
+                let env = self.env.as_deref();
+                let with_db = self.normalize_with_db;
+                let preflight = !self.skip_preflight;
+                generate_plan(
+                    &config,
+                    false,
+                    with_db,
+                    env,
+                    self.format,
+                    self.verify,
+                    preflight,
+                )
+                .await?
+            }
+        };
+        if let Some(path) = &self.out {
+            saved.save(path).await?;
+            if self.format == PlanFormat::Text {
+                println!("\nThe plan is saved to {}.", path.display());
+            }
+        }
+        if let Some(dir) = &self.emit_migration {
+            let verbose = self.format == PlanFormat::Text;
+            if saved.plan.is_empty() {
+                if verbose {
+                    println!("No migration file is written.");
+                }
+            } else {
+                let format = config.output.format.unwrap_or_default().into();
+                let name = self.name.as_deref().unwrap_or("renovate");
+                let files = self
+                    .style
+                    .emit(dir, name, &saved.plan, &saved.rollback, format)
+                    .await?;
+                if verbose {
+                    for file in files {
+                        println!("Migration file {} is written.", file.display());
+                    }
+                }
+            }
+        }
+        let plan = saved.plan;
+        if self.fail_on_hot_locks {
+            let count = plan
+                .steps
+                .iter()
+                .filter(|step| step.lock.is_strong_on(|t| config.is_hot_table(t)))
+                .count();
+            if count > 0 {
+                bail!(
+                    "{} statement(s) in the plan take strong locks on hot tables",
+                    count
+                );
+            }
+        }
         Ok(())
     }
 }
 
-pub(super) async fn generate_plan(remote: bool) -> Result<Vec<String>> {
-    let config = load_config().await?;
+pub(super) async fn generate_plan(
+    config: &RenovateConfig,
+    remote: bool,
+    with_db: bool,
+    env: Option<&str>,
+    format: PlanFormat,
+    verify: bool,
+    preflight: bool,
+) -> Result<SavedPlan> {
+    let config = match env {
+        Some(name) => config.with_env(name)?,
+        None => config.clone(),
+    };
+    // environments are deployed from the local repo, not the local database
+    let remote = remote || env.is_some();
     let db_repo = DatabaseRepo::new(&config);
 
-    let local_schema = if !remote {
-        let sql = LocalRepo::new(&config.output.path).load_sql().await?;
-        db_repo.normalize(&sql).await?
-    } else {
-        db_repo.load().await?
+    let local_schema = load_desired_schema(&db_repo, &config, remote, env, with_db).await?;
+    let remote_schema = load_target_schema(&db_repo, remote).await?;
+    let plan = local_schema
+        .plan(&remote_schema)?
+        .backfill(&config.backfill)?;
+    let plan = add_hooks(plan, &db_repo, &config, remote).await?;
+    let mut rollback = remote_schema.rollback(&local_schema)?;
+    rollback.flag_lossy(&plan);
+
+    let mut saved = SavedPlan::new(plan, rollback, &remote_schema, remote);
+    saved.env = env.map(|s| s.to_owned());
+    if preflight {
+        saved.preflight = db_repo.preflight(&saved).await?;
+    }
+    print_plan(&saved, &config, format)?;
+    if verify && !saved.plan.is_empty() {
+        verify_plan(&db_repo, &saved, &local_schema).await?;
+    }
+    Ok(saved)
+}
+
+/// migration names end up in file names, and the tools split the version off by `_`
+fn parse_migration_name(s: &str) -> Result<String, Error> {
+    if s.is_empty() || !s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        bail!("the migration name should only have letters, digits and `_`");
+    }
+    Ok(s.to_owned())
+}
+
+/// add the hook scripts of the local repo which haven't been applied to the target yet. The
+/// target is not queried if there's no hook
+async fn add_hooks(
+    plan: MigrationPlan,
+    db_repo: &DatabaseRepo,
+    config: &RenovateConfig,
+    remote: bool,
+) -> Result<MigrationPlan> {
+    let mut hooks = LocalRepo::new(&config.output.path).hooks().await?;
+    if hooks.is_empty() {
+        return Ok(plan);
+    }
+    let consumed = db_repo.consumed_hooks(remote).await?;
+    hooks.retain(|hook| !consumed.contains(&hook.name));
+    let plan = plan.with_hooks(&hooks)?;
+    warn_left_out_hooks(&plan, &hooks);
+    Ok(plan)
+}
+
+/// the pending hooks the plan doesn't run stay pending, so they are shown on stderr to not go
+/// unnoticed
+fn warn_left_out_hooks(plan: &MigrationPlan, hooks: &[MigrationHook]) {
+    for hook in plan.left_out_hooks(hooks) {
+        eprintln!(
+            "WARNING: hook {} is not applied, since none of its objects is changed. It stays pending.",
+            hook.name
+        );
+    }
+}
+
+/// load the schema the target database should have after the plan: the normalized local repo,
+/// or the local database when planning for the remote one
+pub(super) async fn load_desired_schema(
+    db_repo: &DatabaseRepo,
+    config: &RenovateConfig,
+    remote: bool,
+    env: Option<&str>,
+    with_db: bool,
+) -> Result<DatabaseSchema> {
+    if remote && env.is_none() {
+        return db_repo.load().await;
+    }
+
+    let roles = match env {
+        Some(name) => config.environment(name)?.roles.clone(),
+        None => Default::default(),
     };
-    let remote_schema = if !remote {
-        db_repo.load().await?
+    let sql = LocalRepo::new(&config.output.path).load_sql().await?;
+    let target_url = if remote {
+        &config.remote_url
     } else {
-        let sql = db_repo.load_sql_string(remote).await?;
-        SqlLoader::new(&sql).load().await?
+        &config.url
+    };
+    let normalizer = Normalizer::with_url(target_url).with_roles(roles);
+    let mut schema = normalize_local(db_repo, &sql, &normalizer, with_db).await?;
+    config.filter().apply(&mut schema);
+    Ok(schema)
+}
+
+/// apply the plan to a shadow clone of the target database, and fail if the result still
+/// differs from the expected schema, i.e. the planner missed something
+pub(super) async fn verify_plan(
+    db_repo: &DatabaseRepo,
+    saved: &SavedPlan,
+    expected: &DatabaseSchema,
+) -> Result<()> {
+    println!("\nVerifying the plan on a shadow clone of the target database...");
+    let leftover = db_repo.verify(saved, expected).await?;
+    if leftover.is_empty() {
+        println!("The plan is verified: the result matches the local schema.");
+        return Ok(());
+    }
+
+    println!("After applying the plan, these statements are still needed:\n");
+    for step in &leftover.steps {
+        println!("  {} {}: {}", step.type_name, step.id, step.sql);
+    }
+    bail!(
+        "The plan is incomplete: {} statement(s) are left after applying it to the shadow clone. Please report the issue, and fix the plan by hand if needed.",
+        leftover.steps.len()
+    )
+}
+
+/// normalize the local schema with the built-in rewrite rules. With `with_db`, the schema is
+/// normalized via a temp database instead, and the objects the rules disagree on are reported
+async fn normalize_local(
+    db_repo: &DatabaseRepo,
+    sql: &str,
+    normalizer: &Normalizer,
+    with_db: bool,
+) -> Result<DatabaseSchema> {
+    let schema = SqlLoader::new(normalizer.normalize(sql)?).load().await?;
+    if !with_db {
+        return Ok(schema);
+    }
+
+    let db_schema = db_repo.normalize(sql).await?;
+    let db_schema = SqlLoader::new(normalizer.rename_roles(&db_schema.sql(true))?)
+        .load()
+        .await?;
+    for object in db_schema.plan(&schema)?.objects() {
+        eprintln!(
+            "WARNING: {} {} is normalized differently without a database.",
+            object.type_name, object.id
+        );
+    }
+    Ok(db_schema)
+}
+
+/// make the plan between two git revisions of the local repo. The SQL files are parsed
+/// directly, so no database is needed
+async fn generate_offline_plan(
+    config: &RenovateConfig,
+    from: &str,
+    to: Option<&str>,
+    format: PlanFormat,
+) -> Result<SavedPlan> {
+    let path = &config.output.path;
+
+    let mut old_schema = GitRevision::new(path, from).load().await?;
+    let mut new_schema = match to {
+        Some(to) => GitRevision::new(path, to).load().await?,
+        None => LocalRepo::new(path).load().await?,
     };
-    let plan = local_schema.plan(&remote_schema, true)?;
+    let filter = config.filter();
+    filter.apply(&mut old_schema);
+    filter.apply(&mut new_schema);
+    // without a database, it's unknown which hooks were applied, so all of them are shown
+    let hooks = LocalRepo::new(path).hooks().await?;
+    let plan = new_schema
+        .plan(&old_schema)?
+        .backfill(&config.backfill)?
+        .with_hooks(&hooks)?;
+    warn_left_out_hooks(&plan, &hooks);
+    let mut rollback = old_schema.rollback(&new_schema)?;
+    rollback.flag_lossy(&plan);
+
+    let saved = SavedPlan::new(plan, rollback, &old_schema, false);
+    print_plan(&saved, config, format)?;
+    Ok(saved)
+}
 
+/// load the schema the plan is applied to
+pub(super) async fn load_target_schema(
+    db_repo: &DatabaseRepo,
+    remote: bool,
+) -> Result<DatabaseSchema> {
+    if !remote {
+        db_repo.load().await
+    } else {
+        db_repo.load_schema(remote).await
+    }
+}
+
+pub(super) fn print_plan(
+    saved: &SavedPlan,
+    config: &RenovateConfig,
+    format: PlanFormat,
+) -> Result<()> {
+    match format {
+        PlanFormat::Text => print_text(saved, config)?,
+        PlanFormat::Json => println!("{}", serde_json::to_string_pretty(saved)?),
+        PlanFormat::Markdown => {
+            print!("{}", saved.plan.to_markdown()?);
+            if !saved.preflight.is_empty() {
+                println!("\n### Preflight checks\n");
+                for result in &saved.preflight {
+                    println!("- {}", result);
+                }
+            }
+            if !saved.plan.is_empty() {
+                print!("{}", saved.rollback.to_markdown_rollback()?);
+            }
+        }
+        PlanFormat::Sql => {
+            print_sql(&saved.plan, config, false)?;
+            // keep stdout the SQL only
+            for result in saved.preflight.iter().filter(|r| r.blocks()) {
+                eprintln!("WARNING: {}", result);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn print_text(saved: &SavedPlan, config: &RenovateConfig) -> Result<()> {
+    let plan = &saved.plan;
     if plan.is_empty() {
         println!("No changes detected.");
-        return Ok(vec![]);
+        return Ok(());
+    }
+
+    let tty = atty::is(atty::Stream::Stdout);
+    if tty {
+        for object in plan.objects() {
+            if object.diff().is_empty() {
+                continue;
+            }
+            let action = match object.action() {
+                MigrationAction::Create => "added",
+                MigrationAction::Alter => "changed",
+                MigrationAction::Drop => "removed",
+            };
+            let diff = colorize_diff(object.diff());
+            println!(
+                "{} {} is {}:\n\n{}",
+                object.type_name, object.id, action, diff
+            );
+        }
+    }
+
+    if plan.has_phase(MigrationPhase::Pre) && plan.has_phase(MigrationPhase::Post) {
+        println!("The following SQLs will be applied before deploying the app (`--phase pre`):\n");
+        print_sql(&plan.phase(MigrationPhase::Pre), config, tty)?;
+        println!(
+            "\nThe following SQLs will be applied after deploying the app (`--phase post`):\n"
+        );
+        print_sql(&plan.phase(MigrationPhase::Post), config, tty)?;
+    } else {
+        println!("The following SQLs will be applied:\n");
+        print_sql(plan, config, tty)?;
+    }
+    print_preflight(saved);
+    print_rollback(&saved.rollback, config)?;
+    Ok(())
+}
+
+/// show what the preflight checks found in the target database
+fn print_preflight(saved: &SavedPlan) {
+    if saved.preflight.is_empty() {
+        return;
     }
+    println!("\nPreflight checks against the target database:\n");
+    for result in &saved.preflight {
+        println!("{}", result);
+    }
+    let failures = saved.preflight_failures();
+    if failures > 0 {
+        println!(
+            "\nWARNING: {} preflight check(s) found existing rows the plan would fail on.",
+            failures
+        );
+    }
+}
+
+pub(super) fn print_rollback(rollback: &MigrationPlan, config: &RenovateConfig) -> Result<()> {
+    println!("\nThe plan could be rolled back by the following SQLs:\n");
+    print_sql(rollback, config, atty::is(atty::Stream::Stdout))?;
 
-    println!("The following SQLs will be applied:\n");
-    for item in plan.iter() {
+    let lossy = rollback.steps.iter().filter(|s| s.lossy).count();
+    if lossy > 0 {
+        println!(
+            "\nWARNING: {} rollback statement(s) can't restore the data lost by the plan.",
+            lossy
+        );
+    }
+    Ok(())
+}
+
+fn print_sql(plan: &MigrationPlan, config: &RenovateConfig, highlight: bool) -> Result<()> {
+    // the hooks are recorded along with them, so that the printed SQL doesn't run them twice
+    for step in &plan.with_hook_records().steps {
+        let lock = &step.lock;
         let formatted = sqlformat::format(
-            item,
+            &step.sql,
             &Default::default(),
             config.output.format.unwrap_or_default().into(),
         );
-        if atty::is(atty::Stream::Stdout) {
+        if highlight {
             println!("{};", highlight_text(&formatted, "sql", None)?);
         } else {
             println!("{};", formatted);
         }
+        if step.lossy {
+            println!("-- WARNING: can't restore the lost data");
+        }
+        if let Some(name) = &step.hook {
+            println!("-- hook {}, recorded by the INSERT below", name);
+        }
+        if step.batch_sql.is_some() {
+            println!("-- `renovate schema apply` runs this in batches; as printed, it updates all rows at once");
+        }
+        if lock.mode.is_some() {
+            let hot = if lock.is_strong_on(|t| config.is_hot_table(t)) {
+                " [hot table]"
+            } else {
+                ""
+            };
+            println!("-- {}{}", lock, hot);
+        }
     }
-    Ok(plan)
+    Ok(())
 }
@@ -0,0 +1,69 @@
+use super::{
+    confirm, git_commit, git_commit_id, git_dirty, load_target_schema, print_rollback, Args,
+    CommandExecutor,
+};
+use crate::{utils::load_config, DatabaseRepo, MigrationPlan, SavedPlan};
+use clap_utils::prelude::*;
+
+#[derive(Parser, Debug, Clone)]
+pub struct SchemaRollbackCommand {
+    #[clap(long, value_parser, default_value = "false")]
+    remote: bool,
+}
+
+#[async_trait]
+impl CommandExecutor for SchemaRollbackCommand {
+    async fn execute(&self, args: &Args) -> Result<(), Error> {
+        let config = load_config().await?;
+        let db_repo = DatabaseRepo::new(&config);
+
+        let record = match db_repo.history(self.remote).await?.into_iter().next() {
+            Some(record) => record,
+            None => {
+                println!("No migrations have been applied yet.");
+                return Ok(());
+            }
+        };
+        let rollback = record.rollback.0;
+        if rollback.is_empty() {
+            println!("Migration #{} has no rollback plan.", record.id);
+            return Ok(());
+        }
+
+        let target = load_target_schema(&db_repo, self.remote).await?;
+        if target.fingerprint() != record.fingerprint {
+            bail!(
+                "The database has changed since migration #{} was applied. Please make a new plan instead.",
+                record.id
+            );
+        }
+
+        println!(
+            "Migration #{} (plan {}) was applied at {} by {}.",
+            record.id, record.plan_id, record.applied_at, record.applied_by
+        );
+        print_rollback(&rollback, &config)?;
+
+        if git_dirty()? {
+            bail!("Your repo is dirty. Please commit the changes before rolling back.");
+        }
+
+        if confirm(args, "\nDo you want to roll back this migration?")? {
+            // the rollback itself can't be rolled back
+            let saved = SavedPlan::new(rollback, MigrationPlan::default(), &target, self.remote);
+            db_repo.apply(&saved, git_commit_id().as_deref()).await?;
+            git_commit(format!(
+                "automatically commit the schema after rolling back migration #{}",
+                record.id
+            ))?;
+            println!(
+                "Successfully rolled back migration #{}.\nYour repo is updated with the latest schema. See `git diff HEAD~1` for details.",
+                record.id
+            );
+        } else {
+            println!("Rollback has been cancelled.");
+        }
+
+        Ok(())
+    }
+}
@@ -1,10 +1,15 @@
+mod analyzer;
 #[cfg(feature = "cli")]
 pub mod commands;
 mod config;
+mod connection;
 mod macros;
+mod normalizer;
 mod parser;
 mod repo;
 mod schema;
+#[cfg(test)]
+mod test_utils;
 mod types;
 mod utils;
 
@@ -12,11 +17,22 @@ use anyhow::Result;
 use async_trait::async_trait;
 use config::RenovateOutputConfig;
 use pg_query::NodeEnum;
+use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
 use std::{collections::BTreeSet, path::PathBuf};
 
-pub use config::RenovateConfig;
+pub use analyzer::{
+    LockMode, MigrationPhase, PreflightCheck, PreflightQuery, PreflightResult, RiskLevel,
+    StatementLock,
+};
+pub use config::{
+    ConfirmPolicy, RenovateApplyConfig, RenovateBackfillConfig, RenovateConfig,
+    RenovateEnvironmentConfig, RenovateFilterConfig,
+};
+pub use normalizer::Normalizer;
 pub use parser::DatabaseSchema;
 pub use repo::git::{BumpVersion, GitRepo};
+pub use repo::{pg_dump_version, ShadowError};
 
 #[async_trait]
 pub trait SchemaLoader {
@@ -69,6 +85,212 @@ pub struct NodeDelta<T> {
     pub changed: BTreeSet<(T, T)>,
 }
 
+/// Ordered migration steps to move the remote state to the local state
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MigrationPlan {
+    pub steps: Vec<MigrationStep>,
+}
+
+/// A single SQL statement of the migration plan, with the schema object it belongs to
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MigrationStep {
+    /// the SQL statement to execute
+    pub sql: String,
+    /// id of the schema object
+    pub id: String,
+    /// database type name of the schema object
+    pub type_name: String,
+    /// what the statement does to the schema object
+    pub action: MigrationAction,
+    /// false if the statement can't run inside a transaction block, e.g. `CREATE INDEX CONCURRENTLY`
+    pub transactional: bool,
+    /// how risky it is to run the statement
+    pub risk: RiskLevel,
+    /// the lock the statement acquires
+    pub lock: StatementLock,
+    /// unified diff of the schema object which generates the statement
+    pub diff: String,
+    /// for rollback steps: the step recreates an object whose data was lost by the forward plan
+    #[serde(default)]
+    pub lossy: bool,
+    /// for backfill steps: the statement updating a batch of the rows `sql` updates. It's run
+    /// repeatedly, each in a transaction of its own, until no more row is updated
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub batch_sql: Option<String>,
+    /// whether the step is applied before or after deploying the app
+    #[serde(default)]
+    pub phase: MigrationPhase,
+    /// for hook steps: the name of the hook script, recorded as consumed when the step is applied
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hook: Option<String>,
+}
+
+/// Kind of change a migration step makes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MigrationAction {
+    Create,
+    Alter,
+    Drop,
+}
+
+/// A migration plan saved to a file, with the fingerprint of the schema it was planned against
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SavedPlan {
+    /// whether the plan targets the remote database server
+    pub remote: bool,
+    /// fingerprint of the target schema when the plan was made
+    pub fingerprint: String,
+    pub plan: MigrationPlan,
+    /// the plan to revert the changes made by `plan`
+    #[serde(default)]
+    pub rollback: MigrationPlan,
+    /// the environment the plan was made for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub env: Option<String>,
+    /// the preflight checks last run against the target
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub preflight: Vec<PreflightResult>,
+    /// the phase `plan` holds the steps of, or None for the whole plan
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub phase: Option<MigrationPhase>,
+}
+
+/// The advisory lock which lets only one apply run on a database at a time, held by the
+/// connection the plan is then applied on. It's released when dropped, as the connection is
+/// closed
+#[derive(Debug)]
+pub struct ApplyLock {
+    conn: PgConnection,
+    url: String,
+}
+
+/// What happened when a plan was applied, statement by statement. Written by
+/// `renovate schema apply --report` for CI to archive
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ApplyReport {
+    /// id of the applied plan
+    pub plan_id: String,
+    /// the environment the plan was applied to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub env: Option<String>,
+    /// url of the database, with the password masked
+    pub target: String,
+    pub started_at: String,
+    pub duration_ms: u64,
+    pub succeeded: bool,
+    pub steps: Vec<StepReport>,
+    /// the statement which failed the apply
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub failure: Option<StepFailure>,
+}
+
+/// A statement of the plan in the apply report
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StepReport {
+    pub sql: String,
+    /// id of the schema object
+    pub id: String,
+    /// database type name of the schema object
+    pub type_name: String,
+    pub status: StepStatus,
+    /// time taken by the last run of the statement
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u64>,
+    /// how many times the statement was run. More than 1 if its batch was retried
+    pub attempts: u32,
+}
+
+/// What happened to a statement when applying the plan
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StepStatus {
+    /// not run, e.g. an earlier statement failed
+    Pending,
+    /// run and committed
+    Applied,
+    /// run, but the transaction was rolled back since a later statement of it failed
+    RolledBack,
+    /// the statement failed
+    Failed,
+}
+
+/// The error of the statement which failed the apply, with the details from postgres
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StepFailure {
+    /// position of the failed statement in the plan, starting from 1. None if the failure is
+    /// not from a statement of the plan, e.g. `COMMIT`
+    pub step: Option<usize>,
+    pub sql: String,
+    /// the SQLSTATE error code, e.g. `55P03` for lock timeouts
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sql_state: Option<String>,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hint: Option<String>,
+    /// whether the transaction of the failed statement was rolled back
+    pub rolled_back: bool,
+    /// how many times the batch of the statement was run
+    pub attempts: u32,
+}
+
+/// The migration last applied to a named environment, saved in the local repo so that it's
+/// easy to see which environment is behind
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EnvironmentState {
+    /// the local git commit the plan was applied from
+    pub commit_id: Option<String>,
+    /// id of the applied plan
+    pub plan_id: String,
+    pub applied_at: String,
+}
+
+/// Migration tools which could run the migration files emitted from a plan
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MigrationStyle {
+    /// `<timestamp>_<name>.up.sql` and `<timestamp>_<name>.down.sql`
+    Sqlx,
+    /// `V<timestamp>__<name>.sql` and the undo migration `U<timestamp>__<name>.sql`
+    Flyway,
+    /// `V<n>__<name>.sql`. Refinery doesn't support down migrations
+    Refinery,
+    /// `<timestamp>_<name>.sql` with `-- migrate:up` and `-- migrate:down` sections
+    Dbmate,
+}
+
+/// An applied migration plan recorded in the `_renovate.migrations` table of the target database
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, sqlx::FromRow)]
+pub struct MigrationRecord {
+    pub id: i64,
+    /// id of the applied plan
+    pub plan_id: String,
+    pub statements: Vec<String>,
+    /// the local git commit the plan was applied from
+    pub commit_id: Option<String>,
+    pub applied_at: String,
+    /// time spent on executing the statements, in milliseconds
+    pub duration_ms: i64,
+    /// the database user who applied the plan
+    pub applied_by: String,
+    /// fingerprint of the schema after the plan was applied
+    pub fingerprint: String,
+    /// the plan to revert the migration
+    pub rollback: sqlx::types::Json<MigrationPlan>,
+    /// `pre` or `post` if only a phase of the plan was applied
+    pub phase: Option<String>,
+}
+
+/// Consecutive steps of the migration plan which belong to the same schema object
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PlanObject<'a> {
+    pub id: &'a str,
+    pub type_name: &'a str,
+    pub steps: &'a [MigrationStep],
+}
+
 /// Diffing two objects to get deltas
 pub trait Differ {
     type Diff: MigrationPlanner;
@@ -132,11 +354,76 @@ pub struct LocalRepo {
     pub path: PathBuf,
 }
 
+/// The files `LocalRepo::format` rewrote, or would rewrite in check mode
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FormattedFiles {
+    pub changed: Vec<PathBuf>,
+    /// files left untouched as they have comments, which the rewrite would drop, although
+    /// their statements are not in the form of pg_dump
+    pub commented: Vec<PathBuf>,
+}
+
+/// A SQL script in the `_hooks` directory of the local repo, e.g. a data migration run with
+/// the steps of the objects it is tagged with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MigrationHook {
+    /// path relative to `_hooks`, e.g. `post/01_split_name.sql`. It's recorded by the name
+    /// once applied, so that it doesn't run twice
+    pub name: String,
+    pub kind: HookKind,
+    /// ids of the objects from the `-- objects:` header. Empty if it's for the whole plan
+    pub objects: Vec<String>,
+    pub sql: String,
+}
+
+/// When a hook runs, relative to the steps of its objects
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum HookKind {
+    /// before the first step of the objects
+    Pre,
+    /// after the pre-deploy steps of the objects, or all of them if they have none
+    Post,
+}
+
+/// Local repository at a git revision, loaded from git objects instead of the working tree
+#[derive(Debug, Clone)]
+pub struct GitRevision {
+    pub path: PathBuf,
+    pub revision: String,
+}
+
+/// Where to load a schema from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SchemaSource {
+    /// a postgres database url, e.g. `postgres://localhost:5432/db`
+    Url(String),
+    /// a repo directory with sql files
+    Dir(PathBuf),
+    /// a single sql file, e.g. a `pg_dump` output
+    File(PathBuf),
+    /// the local repo at a git revision, e.g. `git:main`
+    Git(String),
+}
+
 /// Remote repository
 #[derive(Debug, Clone)]
 pub struct DatabaseRepo {
     url: String,
     remote_url: String,
+    /// server to create temp databases on. Default to the server of `url`
+    shadow_url: Option<String>,
+    /// the schemas and objects to load from the database
+    filter: SchemaFilter,
+    /// timeouts and retries when applying a plan
+    apply: RenovateApplyConfig,
+}
+
+/// The schemas and objects managed by renovate, from `include` and `exclude` in renovate.yml.
+/// Objects not managed are never saved, planned or dropped
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SchemaFilter {
+    include: RenovateFilterConfig,
+    exclude: RenovateFilterConfig,
 }
 
 /// intermediate representation for local and remote repo
@@ -1,11 +1,19 @@
 use clap_utils::prelude::*;
-use renovate::commands::{Args, CommandExecutor};
+use renovate::commands::{Args, CommandExecutor, DriftDetected};
+use std::process::ExitCode;
 
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() -> Result<ExitCode> {
     let args = Args::parse();
     let action = &args.action;
-    action.execute(&args).await?;
+    let code = match action.execute(&args).await {
+        // the drift is printed by the command already
+        Err(e) if e.is::<DriftDetected>() => ExitCode::from(DriftDetected::EXIT_CODE),
+        ret => {
+            ret?;
+            ExitCode::SUCCESS
+        }
+    };
 
     #[cfg(feature = "cli-test")]
     if args.drop_on_exit {
@@ -14,5 +22,5 @@ async fn main() -> Result<()> {
         let repo = DatabaseRepo::new(&config);
         repo.drop_database().await.ok();
     }
-    Ok(())
+    Ok(code)
 }
@@ -0,0 +1,508 @@
+mod role;
+mod table;
+
+use crate::{connection::resolve_url, parser::SchemaId};
+use anyhow::{anyhow, Context, Result};
+use pg_query::{
+    protobuf::{CreateSeqStmt, IndexStmt, RangeVar},
+    Node, NodeEnum,
+};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::PathBuf,
+};
+use url::Url;
+
+/// default schema for the objects without a schema name
+const DEFAULT_SCHEMA: &str = "public";
+
+/// identifiers longer than this are truncated by postgres, `NAMEDATALEN - 1`
+const MAX_IDENTIFIER_LEN: usize = 63;
+
+/// Rewrite schema SQL into the form `pg_dump` dumps it, without a database. See RFC 0002 for the
+/// rewrite rules
+#[derive(Debug, Clone, Default)]
+pub struct Normalizer {
+    /// the role to own the tables and sequences without an explicit owner
+    owner: Option<String>,
+    /// roles to rename in owners, grants and policies, e.g. for a deployment environment
+    roles: BTreeMap<String, String>,
+}
+
+/// Objects collected from all the statements before rewriting any of them
+#[derive(Debug, Default)]
+struct Catalog {
+    /// primary key columns of the tables
+    primary_keys: BTreeMap<String, Vec<String>>,
+    /// user defined types, e.g. enums and composite types
+    types: BTreeSet<SchemaId>,
+    /// names of the constraints and indexes, to avoid generating a duplicated one
+    names: BTreeSet<String>,
+}
+
+impl Normalizer {
+    pub fn new(owner: Option<String>) -> Self {
+        Self {
+            owner,
+            roles: BTreeMap::new(),
+        }
+    }
+
+    /// objects are owned by the user of the database url, the same as normalizing via a temp
+    /// database. The user could also come from `PGUSER` or `PGSERVICE`
+    pub fn with_url(url: &str) -> Self {
+        let owner = Url::parse(&resolve_url(url))
+            .ok()
+            .map(|url| url.username().to_owned())
+            .filter(|user| !user.is_empty());
+        Self::new(owner)
+    }
+
+    pub fn with_roles(mut self, roles: BTreeMap<String, String>) -> Self {
+        self.roles = roles;
+        self
+    }
+
+    /// only rename the roles, e.g. for the sql dumped by pg_dump which is normalized already
+    pub fn rename_roles(&self, sql: &str) -> Result<String> {
+        let mut stmts = parse_stmts(sql)?;
+        for stmt in stmts.iter_mut() {
+            role::rename_roles(stmt, &self.roles);
+        }
+        deparse_stmts(stmts, sql.len())
+    }
+
+    /// rewrite the sql statements. The output could be loaded by `SqlLoader`
+    pub fn normalize(&self, sql: &str) -> Result<String> {
+        let stmts = parse_stmts(sql)?;
+        let mut catalog = Catalog::new(&stmts);
+        self.rewrite(stmts, &mut catalog, sql.len())
+    }
+
+    /// rewrite each of the files on its own. The catalog is collected from all of them first,
+    /// since a file could use the types or reference the tables of another one
+    pub fn normalize_files(&self, files: &[(PathBuf, String)]) -> Result<Vec<String>> {
+        let parsed = files
+            .iter()
+            .map(|(file, sql)| {
+                parse_stmts(sql).with_context(|| format!("Failed to parse {}", file.display()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let mut catalog = Catalog::new(parsed.iter().flatten());
+        files
+            .iter()
+            .zip(parsed)
+            .map(|((file, sql), stmts)| {
+                self.rewrite(stmts, &mut catalog, sql.len())
+                    .with_context(|| format!("Failed to normalize {}", file.display()))
+            })
+            .collect()
+    }
+
+    fn rewrite(&self, stmts: Vec<NodeEnum>, catalog: &mut Catalog, len: usize) -> Result<String> {
+        let mut output = Vec::with_capacity(stmts.len());
+        // defaults, constraints and indexes are created after all the tables, as pg_dump does
+        let mut deferred = Vec::new();
+        let mut owned = BTreeSet::new();
+        let mut relations = Vec::new();
+
+        for mut stmt in stmts {
+            role::rename_roles(&mut stmt, &self.roles);
+            match stmt {
+                NodeEnum::CreateStmt(mut stmt) => {
+                    let rewrite = table::normalize_table(&mut stmt, catalog)?;
+                    relations.push(qualified_name(stmt.relation.as_ref()));
+                    output.push(NodeEnum::CreateStmt(stmt));
+                    for seq in rewrite.sequences {
+                        relations.push(qualified_name(seq.sequence.as_ref()));
+                        output.push(NodeEnum::CreateSeqStmt(Box::new(seq)));
+                    }
+                    output.extend(rewrite.owned_by);
+                    deferred.extend(rewrite.deferred);
+                }
+                NodeEnum::CreateSeqStmt(mut stmt) => {
+                    normalize_sequence(&mut stmt)?;
+                    relations.push(qualified_name(stmt.sequence.as_ref()));
+                    output.push(NodeEnum::CreateSeqStmt(stmt));
+                }
+                NodeEnum::IndexStmt(mut stmt) => {
+                    normalize_index(&mut stmt, catalog);
+                    deferred.push(NodeEnum::IndexStmt(stmt));
+                }
+                NodeEnum::AlterTableStmt(mut stmt) => {
+                    let is_owner = table::normalize_alter_table(&mut stmt, catalog);
+                    if is_owner {
+                        owned.insert(qualified_name(stmt.relation.as_ref()));
+                        output.push(NodeEnum::AlterTableStmt(stmt));
+                    } else {
+                        deferred.push(NodeEnum::AlterTableStmt(stmt));
+                    }
+                }
+                stmt => output.push(stmt),
+            }
+        }
+
+        if let Some(owner) = &self.owner {
+            for name in relations.iter().filter(|name| !owned.contains(*name)) {
+                let sql = format!("ALTER TABLE {} OWNER TO {}", name, quote_ident(owner));
+                output.push(parse_stmt(&sql)?);
+            }
+        }
+        output.extend(deferred);
+        deparse_stmts(output, len)
+    }
+}
+
+impl Catalog {
+    fn new<'a>(stmts: impl IntoIterator<Item = &'a NodeEnum>) -> Self {
+        let mut catalog = Self::default();
+        for stmt in stmts {
+            match stmt {
+                NodeEnum::CreateStmt(stmt) => {
+                    let keys = table::primary_key(stmt);
+                    if !keys.is_empty() {
+                        let id = SchemaId::from(stmt.relation.as_ref()).to_string();
+                        catalog.primary_keys.insert(id, keys);
+                    }
+                }
+                NodeEnum::AlterTableStmt(stmt) => {
+                    let keys = table::added_primary_key(stmt);
+                    if !keys.is_empty() {
+                        let id = SchemaId::from(stmt.relation.as_ref()).to_string();
+                        catalog.primary_keys.insert(id, keys);
+                    }
+                }
+                NodeEnum::CreateEnumStmt(stmt) => {
+                    catalog.types.insert(names_to_id(&stmt.type_name));
+                }
+                NodeEnum::CreateDomainStmt(stmt) => {
+                    catalog.types.insert(names_to_id(&stmt.domainname));
+                }
+                NodeEnum::CompositeTypeStmt(stmt) => {
+                    catalog.types.insert(SchemaId::from(stmt.typevar.as_ref()));
+                }
+                _ => {}
+            }
+        }
+        catalog
+    }
+
+    /// pick a name in the schema which is not used yet, like `ChooseRelationName` of postgres.
+    /// A number is appended to the label for duplicates, e.g. `foo_check1`
+    fn choose_name(
+        &mut self,
+        schema: &str,
+        name1: &str,
+        name2: Option<&str>,
+        label: &str,
+    ) -> String {
+        let mut candidate = make_object_name(name1, name2, label);
+        let mut n = 0;
+        while !self.names.insert(format!("{}.{}", schema, candidate)) {
+            n += 1;
+            candidate = make_object_name(name1, name2, &format!("{}{}", label, n));
+        }
+        candidate
+    }
+
+    /// remember a name given by the user
+    fn use_name(&mut self, schema: &str, name: &str) {
+        self.names.insert(format!("{}.{}", schema, name));
+    }
+}
+
+/// name an object like `makeObjectName` of postgres, e.g. `foo_name_key`. The names are cut,
+/// the longer one first, so that the name with the label fits in `NAMEDATALEN - 1` bytes
+fn make_object_name(name1: &str, name2: Option<&str>, label: &str) -> String {
+    let mut overhead = label.len() + 1;
+    let mut name2_len = 0;
+    if let Some(name2) = name2 {
+        name2_len = name2.len();
+        overhead += 1;
+    }
+    let available = MAX_IDENTIFIER_LEN.saturating_sub(overhead);
+    let mut name1_len = name1.len();
+    while name1_len + name2_len > available {
+        if name1_len > name2_len {
+            name1_len -= 1;
+        } else {
+            name2_len -= 1;
+        }
+    }
+
+    let mut name = clip(name1, name1_len).to_owned();
+    if let Some(name2) = name2 {
+        name.push('_');
+        name.push_str(clip(name2, name2_len));
+    }
+    name.push('_');
+    name.push_str(label);
+    name
+}
+
+/// cut the string to at most `len` bytes, without splitting a character, like `pg_mbcliplen`
+fn clip(s: &str, len: usize) -> &str {
+    let mut len = len.min(s.len());
+    while !s.is_char_boundary(len) {
+        len -= 1;
+    }
+    &s[..len]
+}
+
+/// sequences are dumped with all the options, and without `AS bigint` since it is the default
+fn normalize_sequence(stmt: &mut CreateSeqStmt) -> Result<()> {
+    qualify(stmt.sequence.as_mut());
+
+    let parsed = parse_stmt(
+        "CREATE SEQUENCE s START WITH 1 INCREMENT BY 1 NO MINVALUE NO MAXVALUE CACHE 1",
+    )?;
+    let defaults = match parsed {
+        NodeEnum::CreateSeqStmt(seq) => seq.options,
+        _ => unreachable!("should be a create sequence statement"),
+    };
+
+    let name_of = |node: &Node| match &node.node {
+        Some(NodeEnum::DefElem(elem)) => elem.defname.clone(),
+        _ => String::new(),
+    };
+    let is_bigint = |node: &Node| match &node.node {
+        Some(NodeEnum::DefElem(elem)) if elem.defname == "as" => {
+            matches!(elem.arg.as_deref().and_then(|n| n.node.as_ref()),
+                Some(NodeEnum::TypeName(t)) if table::is_type(t, "int8"))
+        }
+        _ => false,
+    };
+
+    stmt.options.retain(|n| !is_bigint(n));
+    for option in defaults {
+        let name = name_of(&option);
+        if !stmt.options.iter().any(|n| name_of(n) == name) {
+            stmt.options.push(option);
+        }
+    }
+
+    const ORDER: [&str; 7] = [
+        "as",
+        "start",
+        "increment",
+        "minvalue",
+        "maxvalue",
+        "cache",
+        "cycle",
+    ];
+    stmt.options.sort_by_key(|n| {
+        let name = name_of(n);
+        ORDER.iter().position(|o| *o == name).unwrap_or(ORDER.len())
+    });
+    Ok(())
+}
+
+/// index without a name is named by its columns, e.g. `foo_name_idx`
+fn normalize_index(stmt: &mut IndexStmt, catalog: &mut Catalog) {
+    qualify(stmt.relation.as_mut());
+    let id = SchemaId::from(stmt.relation.as_ref());
+    if stmt.idxname.is_empty() {
+        let columns = stmt
+            .index_params
+            .iter()
+            .map(|n| match &n.node {
+                Some(NodeEnum::IndexElem(elem)) if !elem.name.is_empty() => elem.name.clone(),
+                _ => "expr".to_owned(),
+            })
+            .collect::<Vec<_>>()
+            .join("_");
+        stmt.idxname = catalog.choose_name(&id.schema, &id.name, Some(&columns), "idx");
+    } else {
+        catalog.use_name(&id.schema, &stmt.idxname);
+    }
+}
+
+/// set the default schema if the relation doesn't have one
+fn qualify(relation: Option<&mut RangeVar>) {
+    if let Some(relation) = relation {
+        if relation.schemaname.is_empty() {
+            relation.schemaname = DEFAULT_SCHEMA.to_owned();
+        }
+    }
+}
+
+pub(crate) fn qualified_name(relation: Option<&RangeVar>) -> String {
+    let id = SchemaId::from(relation);
+    format!("{}.{}", quote_ident(&id.schema), quote_ident(&id.name))
+}
+
+fn names_to_id(names: &[Node]) -> SchemaId {
+    let names: Vec<String> = names
+        .iter()
+        .filter_map(|n| match &n.node {
+            Some(NodeEnum::String(s)) => Some(s.str.clone()),
+            _ => None,
+        })
+        .collect();
+    let names: Vec<&str> = names.iter().map(|s| s.as_str()).collect();
+    SchemaId::new_with(&names)
+}
+
+/// identifiers are always quoted in the generated SQL, so that keywords and upper case names
+/// are kept as is
+pub(crate) fn quote_ident(ident: &str) -> String {
+    format!("\"{}\"", ident.replace('"', "\"\""))
+}
+
+fn parse_stmts(sql: &str) -> Result<Vec<NodeEnum>> {
+    let parsed = pg_query::parse(sql).with_context(|| "Failed to parse SQL statements")?;
+    Ok(parsed
+        .protobuf
+        .stmts
+        .into_iter()
+        .filter_map(|s| s.stmt.and_then(|n| n.node))
+        .collect())
+}
+
+fn deparse_stmts(stmts: Vec<NodeEnum>, capacity: usize) -> Result<String> {
+    let mut sql = String::with_capacity(capacity);
+    for stmt in stmts {
+        sql.push_str(&stmt.deparse()?);
+        sql.push_str(";\n");
+    }
+    Ok(sql)
+}
+
+pub(crate) fn parse_stmt(sql: &str) -> Result<NodeEnum> {
+    pg_query::parse(sql)?
+        .protobuf
+        .stmts
+        .into_iter()
+        .next()
+        .and_then(|s| s.stmt.and_then(|n| n.node))
+        .ok_or_else(|| anyhow!("no statement in {}", sql))
+}
+
+fn string_node(s: impl Into<String>) -> Node {
+    Node {
+        node: Some(NodeEnum::String(pg_query::protobuf::String {
+            str: s.into(),
+        })),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{SchemaLoader, SqlLoader};
+
+    fn normalize(sql: &str) -> Result<String> {
+        Normalizer::new(Some("postgres".to_owned())).normalize(sql)
+    }
+
+    #[test]
+    fn normalizer_should_rewrite_create_table_like_pg_dump() -> Result<()> {
+        let sql = "CREATE TABLE foo (
+            id2 serial not null primary key check((id2>5)),
+            name text default 'tyrchen',
+            CHECK (name ~* '^[a-z][a-z0-9]{5,}$')
+        )";
+        let output = normalize(sql)?;
+        let expected = [
+            "CREATE TABLE public.foo (id2 int NOT NULL, name text DEFAULT 'tyrchen'::text, CONSTRAINT foo_id2_check CHECK (id2 > 5), CONSTRAINT foo_name_check CHECK (name ~* '^[a-z][a-z0-9]{5,}$'));",
+            "CREATE SEQUENCE public.foo_id2_seq AS int START 1 INCREMENT 1 NO MINVALUE NO MAXVALUE CACHE 1;",
+            "ALTER SEQUENCE public.foo_id2_seq OWNED BY public.foo.id2;",
+            "ALTER TABLE public.foo OWNER TO postgres;",
+            "ALTER TABLE public.foo_id2_seq OWNER TO postgres;",
+            "ALTER TABLE ONLY public.foo ALTER COLUMN id2 SET DEFAULT nextval('public.foo_id2_seq'::regclass);",
+            "ALTER TABLE ONLY public.foo ADD CONSTRAINT foo_pkey PRIMARY KEY (id2);",
+        ];
+        for stmt in expected {
+            assert!(output.contains(stmt), "{} not found in:\n{}", stmt, output);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn normalizer_should_move_keys_to_alter_table() -> Result<()> {
+        let sql = "CREATE TABLE users (id int8 PRIMARY KEY, email varchar(64) UNIQUE);
+        CREATE TABLE posts (id bigint, user_id bigint REFERENCES users ON DELETE CASCADE, UNIQUE (id, user_id));
+        CREATE INDEX ON posts (user_id)";
+        let output = normalize(sql)?;
+        let expected = [
+            "CREATE TABLE public.users (id bigint NOT NULL, email varchar(64));",
+            "CREATE TABLE public.posts (id bigint, user_id bigint);",
+            "ALTER TABLE ONLY public.users ADD CONSTRAINT users_pkey PRIMARY KEY (id);",
+            "ALTER TABLE ONLY public.users ADD CONSTRAINT users_email_key UNIQUE (email);",
+            "ALTER TABLE ONLY public.posts ADD CONSTRAINT posts_user_id_fkey FOREIGN KEY (user_id) REFERENCES public.users",
+            "ALTER TABLE ONLY public.posts ADD CONSTRAINT posts_id_user_id_key UNIQUE (id, user_id);",
+            "CREATE INDEX posts_user_id_idx ON public.posts USING btree (user_id);",
+        ];
+        for stmt in expected {
+            assert!(output.contains(stmt), "{} not found in:\n{}", stmt, output);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn normalizer_should_truncate_long_names_like_postgres() -> Result<()> {
+        let table = "a_table_with_a_very_long_name_to_test_the_truncation_of_names";
+        let sql = format!(
+            "CREATE TABLE {table} (id serial PRIMARY KEY, a_column_with_a_long_name text UNIQUE, b text UNIQUE);
+            CREATE TABLE {table}_2 (b text UNIQUE);",
+            table = table
+        );
+        let output = normalize(&sql)?;
+        // the names postgres generates for the tables
+        let expected = [
+            "CREATE SEQUENCE public.a_table_with_a_very_long_name_to_test_the_truncation_of__id_seq",
+            "CONSTRAINT a_table_with_a_very_long_name_to_test_the_truncation_of_na_pkey PRIMARY KEY",
+            "CONSTRAINT a_table_with_a_very_long_name_to__a_column_with_a_long_name_key UNIQUE",
+            "CONSTRAINT a_table_with_a_very_long_name_to_test_the_truncation_of_n_b_key UNIQUE",
+            // the names of both tables are cut to the same, so the second one gets a number
+            "CONSTRAINT a_table_with_a_very_long_name_to_test_the_truncation_of__b_key1 UNIQUE",
+        ];
+        for stmt in expected {
+            assert!(output.contains(stmt), "{} not found in:\n{}", stmt, output);
+        }
+        assert_eq!(clip("tablé", 5), "tabl");
+        Ok(())
+    }
+
+    #[test]
+    fn normalizer_should_keep_pg_dump_output_as_is() -> Result<()> {
+        let sql = "CREATE TABLE public.todos (id bigint NOT NULL, title text DEFAULT 'todo'::text);
+        ALTER TABLE public.todos OWNER TO postgres;
+        CREATE SEQUENCE public.todos_id_seq START WITH 1 INCREMENT BY 1 NO MINVALUE NO MAXVALUE CACHE 1;
+        ALTER TABLE public.todos_id_seq OWNER TO postgres;
+        ALTER TABLE ONLY public.todos ALTER COLUMN id SET DEFAULT nextval('public.todos_id_seq'::regclass);
+        ALTER TABLE ONLY public.todos ADD CONSTRAINT todos_pkey PRIMARY KEY (id);";
+        let once = normalize(sql)?;
+        assert_eq!(normalize(&once)?, once);
+        assert_eq!(once.matches("OWNER TO").count(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn normalizer_should_rename_roles() -> Result<()> {
+        let roles = BTreeMap::from([("app".to_owned(), "app_eu".to_owned())]);
+        let normalizer = Normalizer::new(None).with_roles(roles);
+        let sql = "ALTER TABLE public.todos OWNER TO app;
+        GRANT SELECT ON TABLE public.todos TO app, readonly";
+        let output = normalizer.rename_roles(sql)?;
+        assert!(output.contains("OWNER TO app_eu;"));
+        assert!(output.contains("TO app_eu, readonly;"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn normalized_schema_should_match_pg_dump_schema() -> Result<()> {
+        let local = "CREATE TABLE todos (id bigserial PRIMARY KEY NOT NULL, title text)";
+        let dumped = "CREATE TABLE public.todos (id bigint NOT NULL, title text);
+        ALTER TABLE public.todos OWNER TO postgres;
+        CREATE SEQUENCE public.todos_id_seq START WITH 1 INCREMENT BY 1 NO MINVALUE NO MAXVALUE CACHE 1;
+        ALTER TABLE public.todos_id_seq OWNER TO postgres;
+        ALTER SEQUENCE public.todos_id_seq OWNED BY public.todos.id;
+        ALTER TABLE ONLY public.todos ALTER COLUMN id SET DEFAULT nextval('public.todos_id_seq'::regclass);
+        ALTER TABLE ONLY public.todos ADD CONSTRAINT todos_pkey PRIMARY KEY (id);";
+        let local = SqlLoader::new(normalize(local)?).load().await?;
+        let dumped = SqlLoader::new(dumped).load().await?;
+        assert!(local.plan(&dumped)?.is_empty());
+        Ok(())
+    }
+}
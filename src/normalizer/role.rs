@@ -0,0 +1,41 @@
+use pg_query::{protobuf::RoleSpec, Node, NodeEnum};
+use std::collections::BTreeMap;
+
+/// rename the roles referenced by owners, grants and policies
+pub(super) fn rename_roles(stmt: &mut NodeEnum, roles: &BTreeMap<String, String>) {
+    if roles.is_empty() {
+        return;
+    }
+    let rename = |role: &mut RoleSpec| {
+        if let Some(name) = roles.get(&role.rolename) {
+            role.rolename = name.clone();
+        }
+    };
+    let rename_all = |nodes: &mut Vec<Node>| {
+        for node in nodes.iter_mut() {
+            if let Some(NodeEnum::RoleSpec(role)) = node.node.as_mut() {
+                rename(role);
+            }
+        }
+    };
+
+    match stmt {
+        NodeEnum::AlterTableStmt(stmt) => {
+            for node in stmt.cmds.iter_mut() {
+                if let Some(NodeEnum::AlterTableCmd(cmd)) = node.node.as_mut() {
+                    if let Some(role) = cmd.newowner.as_mut() {
+                        rename(role);
+                    }
+                }
+            }
+        }
+        NodeEnum::AlterOwnerStmt(stmt) => {
+            if let Some(role) = stmt.newowner.as_mut() {
+                rename(role);
+            }
+        }
+        NodeEnum::GrantStmt(stmt) => rename_all(&mut stmt.grantees),
+        NodeEnum::CreatePolicyStmt(stmt) => rename_all(&mut stmt.roles),
+        _ => {}
+    }
+}
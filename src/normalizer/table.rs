@@ -0,0 +1,515 @@
+use super::{
+    make_object_name, normalize_sequence, parse_stmt, qualified_name, qualify, quote_ident,
+    string_node, Catalog, DEFAULT_SCHEMA,
+};
+use crate::parser::{utils::node_to_string, SchemaId};
+use anyhow::Result;
+use pg_query::{
+    protobuf::{
+        AlterTableStmt, AlterTableType, ColumnDef, ConstrType, Constraint, CreateSeqStmt,
+        CreateStmt, RangeVar, TypeCast, TypeName,
+    },
+    Node, NodeEnum,
+};
+use std::collections::BTreeSet;
+
+/// Statements generated from a `CREATE TABLE`
+#[derive(Debug, Default)]
+pub(super) struct TableRewrite {
+    /// sequences for the serial columns
+    pub sequences: Vec<CreateSeqStmt>,
+    /// `ALTER SEQUENCE ... OWNED BY` for the sequences
+    pub owned_by: Vec<NodeEnum>,
+    /// column defaults and constraints moved to `ALTER TABLE ONLY`
+    pub deferred: Vec<NodeEnum>,
+}
+
+/// type names without `pg_catalog` which postgres resolves to the builtin types, e.g. `int4`.
+/// The sql standard names like `integer` are parsed as `pg_catalog.int4` already
+const BUILTIN_TYPES: [&str; 10] = [
+    "int2",
+    "int4",
+    "int8",
+    "float4",
+    "float8",
+    "bool",
+    "bpchar",
+    "timestamptz",
+    "timetz",
+    "varbit",
+];
+
+/// serial types, with the integer type and the `AS` clause of their sequences
+const SERIAL_TYPES: [(&str, &str, Option<&str>); 6] = [
+    ("smallserial", "int2", Some("smallint")),
+    ("serial2", "int2", Some("smallint")),
+    ("serial", "int4", Some("integer")),
+    ("serial4", "int4", Some("integer")),
+    ("bigserial", "int8", None),
+    ("serial8", "int8", None),
+];
+
+/// rewrite the table the way pg_dump dumps it:
+///
+/// 1. check constraints are moved to the table level and named, e.g. `foo_id_check`.
+/// 2. primary key, unique, foreign key and exclusion constraints are moved to
+///    `ALTER TABLE ONLY ... ADD CONSTRAINT`, and named like postgres does.
+/// 3. serial columns become integer columns with a sequence as the default value.
+/// 4. type names are canonicalized, and string defaults are casted to the column type.
+pub(super) fn normalize_table(
+    stmt: &mut CreateStmt,
+    catalog: &mut Catalog,
+) -> Result<TableRewrite> {
+    qualify(stmt.relation.as_mut());
+    let relation = stmt.relation.clone().unwrap_or_default();
+    let id = SchemaId::from(&relation);
+    let primary_key = catalog
+        .primary_keys
+        .get(&id.to_string())
+        .cloned()
+        .unwrap_or_default();
+
+    // names given by the user take precedence
+    for constraint in stmt.table_elts.iter().flat_map(constraints_of) {
+        if !constraint.conname.is_empty() {
+            catalog.use_name(&id.schema, &constraint.conname);
+        }
+    }
+
+    let mut rewrite = TableRewrite::default();
+    let mut elts = Vec::with_capacity(stmt.table_elts.len());
+    let mut checks = Vec::new();
+    let mut moved = Vec::new();
+
+    for node in stmt.table_elts.drain(..) {
+        match node.node {
+            Some(NodeEnum::ColumnDef(mut column)) => {
+                let col = column.colname.clone();
+                if let Some(seq) = expand_serial(&mut column, &id)? {
+                    let seq_name = qualified_name(seq.sequence.as_ref());
+                    let sql = format!(
+                        "ALTER SEQUENCE {} OWNED BY {}.{}",
+                        seq_name,
+                        qualified_name(Some(&relation)),
+                        quote_ident(&col)
+                    );
+                    rewrite.owned_by.push(parse_stmt(&sql)?);
+                    let sql = format!(
+                        "ALTER TABLE ONLY {} ALTER COLUMN {} SET DEFAULT nextval({}::regclass)",
+                        qualified_name(Some(&relation)),
+                        quote_ident(&col),
+                        quote_literal(&SchemaId::from(seq.sequence.as_ref()).to_string())
+                    );
+                    rewrite.deferred.push(parse_stmt(&sql)?);
+                    rewrite.sequences.push(seq);
+                }
+                if let Some(type_name) = column.type_name.as_mut() {
+                    canonicalize_type(type_name, catalog);
+                }
+
+                let constraints = std::mem::take(&mut column.constraints);
+                let mut kept = Vec::new();
+                for mut constraint in constraints.into_iter().filter_map(into_constraint) {
+                    match constraint.contype() {
+                        ConstrType::ConstrNull => {}
+                        ConstrType::ConstrNotnull => kept.push(constraint),
+                        ConstrType::ConstrDefault => {
+                            cast_string_default(&mut constraint, column.type_name.as_ref());
+                            kept.push(constraint);
+                        }
+                        ConstrType::ConstrCheck => checks.push(constraint),
+                        ConstrType::ConstrPrimary | ConstrType::ConstrUnique => {
+                            constraint.keys = vec![string_node(&col)];
+                            moved.push(constraint);
+                        }
+                        ConstrType::ConstrForeign => {
+                            constraint.fk_attrs = vec![string_node(&col)];
+                            moved.push(constraint);
+                        }
+                        ConstrType::ConstrAttrDeferrable => {
+                            if let Some(last) = moved.last_mut() {
+                                last.deferrable = true;
+                            }
+                        }
+                        ConstrType::ConstrAttrDeferred => {
+                            if let Some(last) = moved.last_mut() {
+                                last.initdeferred = true;
+                            }
+                        }
+                        ConstrType::ConstrAttrNotDeferrable | ConstrType::ConstrAttrImmediate => {}
+                        _ => kept.push(constraint),
+                    }
+                }
+                if primary_key.contains(&col) {
+                    let mut not_null = Constraint::default();
+                    not_null.set_contype(ConstrType::ConstrNotnull);
+                    kept.push(not_null);
+                }
+                column.constraints = order_column_constraints(kept);
+                elts.push(Node {
+                    node: Some(NodeEnum::ColumnDef(column)),
+                });
+            }
+            Some(NodeEnum::Constraint(constraint)) => match constraint.contype() {
+                ConstrType::ConstrCheck => checks.push(*constraint),
+                ConstrType::ConstrPrimary
+                | ConstrType::ConstrUnique
+                | ConstrType::ConstrForeign
+                | ConstrType::ConstrExclusion => moved.push(*constraint),
+                _ => elts.push(Node {
+                    node: Some(NodeEnum::Constraint(constraint)),
+                }),
+            },
+            node => elts.push(Node { node }),
+        }
+    }
+
+    // pg_dump sorts the check constraints by name
+    let mut checks: Vec<_> = checks
+        .into_iter()
+        .map(|mut c| {
+            name_constraint(&mut c, &id, catalog);
+            c
+        })
+        .collect();
+    checks.sort_by(|a, b| a.conname.cmp(&b.conname));
+    elts.extend(checks.into_iter().map(constraint_node));
+    stmt.table_elts = elts;
+
+    for mut constraint in moved {
+        name_constraint(&mut constraint, &id, catalog);
+        normalize_foreign_key(&mut constraint, catalog);
+        rewrite
+            .deferred
+            .push(add_constraint(&relation, constraint)?);
+    }
+    Ok(rewrite)
+}
+
+/// qualify the table of `ALTER TABLE`, and name the added constraint. Returns true if it is an
+/// `OWNER TO` statement
+pub(super) fn normalize_alter_table(stmt: &mut AlterTableStmt, catalog: &mut Catalog) -> bool {
+    qualify(stmt.relation.as_mut());
+    let id = SchemaId::from(stmt.relation.as_ref());
+
+    let mut is_owner = false;
+    let mut only = false;
+    for node in stmt.cmds.iter_mut() {
+        if let Some(NodeEnum::AlterTableCmd(cmd)) = node.node.as_mut() {
+            match cmd.subtype() {
+                AlterTableType::AtChangeOwner => is_owner = true,
+                AlterTableType::AtColumnDefault => only = true,
+                AlterTableType::AtAddConstraint => {
+                    only = true;
+                    if let Some(NodeEnum::Constraint(c)) =
+                        cmd.def.as_mut().and_then(|n| n.node.as_mut())
+                    {
+                        name_constraint(c, &id, catalog);
+                        normalize_foreign_key(c, catalog);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    // pg_dump adds defaults and constraints to the table only, not its children
+    if only {
+        if let Some(relation) = stmt.relation.as_mut() {
+            relation.inh = false;
+        }
+    }
+    is_owner
+}
+
+/// primary key columns defined in `CREATE TABLE`
+pub(super) fn primary_key(stmt: &CreateStmt) -> Vec<String> {
+    for node in stmt.table_elts.iter().filter_map(|n| n.node.as_ref()) {
+        match node {
+            NodeEnum::ColumnDef(column) => {
+                let is_pk = column.constraints.iter().any(|n| {
+                    matches!(&n.node, Some(NodeEnum::Constraint(c)) if c.contype() == ConstrType::ConstrPrimary)
+                });
+                if is_pk {
+                    return vec![column.colname.clone()];
+                }
+            }
+            NodeEnum::Constraint(c) if c.contype() == ConstrType::ConstrPrimary => {
+                return names(&c.keys);
+            }
+            _ => {}
+        }
+    }
+    vec![]
+}
+
+/// primary key columns added by `ALTER TABLE ... ADD PRIMARY KEY`
+pub(super) fn added_primary_key(stmt: &AlterTableStmt) -> Vec<String> {
+    stmt.cmds
+        .iter()
+        .filter_map(|n| match &n.node {
+            Some(NodeEnum::AlterTableCmd(cmd)) => cmd.def.as_ref().and_then(|n| n.node.as_ref()),
+            _ => None,
+        })
+        .find_map(|n| match n {
+            NodeEnum::Constraint(c) if c.contype() == ConstrType::ConstrPrimary => {
+                Some(names(&c.keys))
+            }
+            _ => None,
+        })
+        .unwrap_or_default()
+}
+
+/// whether the type is the builtin type, e.g. `pg_catalog.int8`
+pub(super) fn is_type(type_name: &TypeName, name: &str) -> bool {
+    let names = names(&type_name.names);
+    match names.as_slice() {
+        [n] => n == name,
+        [schema, n] => schema == "pg_catalog" && n == name,
+        _ => false,
+    }
+}
+
+/// replace the serial type with the integer type, and generate the sequence for it
+fn expand_serial(column: &mut ColumnDef, table: &SchemaId) -> Result<Option<CreateSeqStmt>> {
+    let type_name = match column.type_name.as_mut() {
+        Some(t) if t.names.len() == 1 && t.array_bounds.is_empty() => t,
+        _ => return Ok(None),
+    };
+    let name = names(&type_name.names).join("");
+    let (int_type, seq_type) = match SERIAL_TYPES.iter().find(|(n, _, _)| *n == name) {
+        Some((_, int_type, seq_type)) => (*int_type, *seq_type),
+        None => return Ok(None),
+    };
+    type_name.names = vec![string_node("pg_catalog"), string_node(int_type)];
+
+    let is_not_null = column.constraints.iter().any(|n| {
+        matches!(&n.node, Some(NodeEnum::Constraint(c)) if c.contype() == ConstrType::ConstrNotnull)
+    });
+    if !is_not_null {
+        let mut not_null = Constraint::default();
+        not_null.set_contype(ConstrType::ConstrNotnull);
+        column.constraints.push(constraint_node(not_null));
+    }
+
+    let seq = SchemaId::new(
+        &table.schema,
+        make_object_name(&table.name, Some(&column.colname), "seq"),
+    );
+    let sql = format!(
+        "CREATE SEQUENCE {}.{}{}",
+        quote_ident(&seq.schema),
+        quote_ident(&seq.name),
+        seq_type.map(|t| format!(" AS {}", t)).unwrap_or_default()
+    );
+    match parse_stmt(&sql)? {
+        NodeEnum::CreateSeqStmt(mut stmt) => {
+            normalize_sequence(&mut stmt)?;
+            Ok(Some(*stmt))
+        }
+        _ => unreachable!("should be a create sequence statement"),
+    }
+}
+
+/// `int4` is the same type as `integer`, but parsed differently
+fn canonicalize_type(type_name: &mut TypeName, catalog: &Catalog) {
+    if type_name.names.len() != 1 {
+        return;
+    }
+    let name = names(&type_name.names).join("");
+    if BUILTIN_TYPES.contains(&name.as_str()) {
+        type_name.names.insert(0, string_node("pg_catalog"));
+    } else if catalog
+        .types
+        .contains(&SchemaId::new(DEFAULT_SCHEMA, &name))
+    {
+        type_name.names.insert(0, string_node(DEFAULT_SCHEMA));
+    }
+}
+
+/// pg_dump shows the type of string defaults, e.g. `DEFAULT 'tyrchen'::text`
+fn cast_string_default(constraint: &mut Constraint, type_name: Option<&TypeName>) {
+    let is_string = matches!(
+        constraint.raw_expr.as_deref().and_then(|n| n.node.as_ref()),
+        Some(NodeEnum::AConst(c)) if matches!(c.val.as_deref().and_then(|v| v.node.as_ref()), Some(NodeEnum::String(_)))
+    );
+    if let (true, Some(type_name)) = (is_string, type_name) {
+        let type_name = TypeName {
+            names: type_name.names.clone(),
+            array_bounds: type_name.array_bounds.clone(),
+            typemod: -1,
+            location: -1,
+            ..Default::default()
+        };
+        let cast = TypeCast {
+            arg: constraint.raw_expr.take(),
+            type_name: Some(type_name),
+            location: -1,
+        };
+        constraint.raw_expr = Some(Box::new(Node {
+            node: Some(NodeEnum::TypeCast(Box::new(cast))),
+        }));
+    }
+}
+
+/// pg_dump shows the default value before `NOT NULL`, and only one `NOT NULL`
+fn order_column_constraints(constraints: Vec<Constraint>) -> Vec<Node> {
+    let rank = |c: &Constraint| match c.contype() {
+        ConstrType::ConstrDefault | ConstrType::ConstrGenerated => 0,
+        ConstrType::ConstrNotnull => 1,
+        _ => 2,
+    };
+    let mut constraints = constraints;
+    constraints.sort_by_key(rank);
+    constraints.dedup_by(|a, b| {
+        a.contype() == ConstrType::ConstrNotnull && b.contype() == ConstrType::ConstrNotnull
+    });
+    constraints.into_iter().map(constraint_node).collect()
+}
+
+/// name the constraint like postgres does, e.g. `foo_pkey`, `foo_name_key`, `foo_user_id_fkey`
+fn name_constraint(constraint: &mut Constraint, table: &SchemaId, catalog: &mut Catalog) {
+    if !constraint.conname.is_empty() {
+        return;
+    }
+    let (columns, label) = match constraint.contype() {
+        ConstrType::ConstrPrimary => (None, "pkey"),
+        ConstrType::ConstrUnique => (Some(names(&constraint.keys).join("_")), "key"),
+        ConstrType::ConstrForeign => (Some(names(&constraint.fk_attrs).join("_")), "fkey"),
+        ConstrType::ConstrExclusion => {
+            let columns: Vec<String> = constraint
+                .exclusions
+                .iter()
+                .filter_map(|n| match &n.node {
+                    Some(NodeEnum::List(l)) => l.items.first(),
+                    _ => None,
+                })
+                .map(|n| match &n.node {
+                    Some(NodeEnum::IndexElem(e)) if !e.name.is_empty() => e.name.clone(),
+                    _ => "expr".to_owned(),
+                })
+                .collect();
+            (Some(columns.join("_")), "excl")
+        }
+        _ => {
+            // check constraints are named by the column only if one column is referenced
+            let mut columns = BTreeSet::new();
+            if let Some(expr) = constraint.raw_expr.as_deref() {
+                column_refs(expr, &mut columns);
+            }
+            match columns.len() {
+                1 => (columns.into_iter().next(), "check"),
+                _ => (None, "check"),
+            }
+        }
+    };
+    constraint.conname = catalog.choose_name(&table.schema, &table.name, columns.as_deref(), label);
+}
+
+/// pg_dump shows the referenced table with schema, and the referenced columns
+fn normalize_foreign_key(constraint: &mut Constraint, catalog: &Catalog) {
+    if constraint.contype() != ConstrType::ConstrForeign {
+        return;
+    }
+    qualify(constraint.pktable.as_mut());
+    if constraint.pk_attrs.is_empty() {
+        let id = SchemaId::from(constraint.pktable.as_ref()).to_string();
+        if let Some(keys) = catalog.primary_keys.get(&id) {
+            constraint.pk_attrs = keys.iter().map(string_node).collect();
+        }
+    }
+}
+
+fn add_constraint(relation: &RangeVar, constraint: Constraint) -> Result<NodeEnum> {
+    let sql = format!(
+        "ALTER TABLE ONLY {} ADD CONSTRAINT {} CHECK (true)",
+        qualified_name(Some(relation)),
+        quote_ident(&constraint.conname)
+    );
+    let mut stmt = match parse_stmt(&sql)? {
+        NodeEnum::AlterTableStmt(stmt) => stmt,
+        _ => unreachable!("should be an alter table statement"),
+    };
+    if let Some(NodeEnum::AlterTableCmd(cmd)) = stmt.cmds[0].node.as_mut() {
+        cmd.def = Some(Box::new(constraint_node(constraint)));
+    }
+    Ok(NodeEnum::AlterTableStmt(stmt))
+}
+
+/// collect the columns referenced by the expression
+fn column_refs(node: &Node, columns: &mut BTreeSet<String>) {
+    let children: Vec<&Node> = match &node.node {
+        Some(NodeEnum::ColumnRef(c)) => {
+            if let Some(name) = c.fields.last().and_then(node_to_string) {
+                columns.insert(name);
+            }
+            vec![]
+        }
+        Some(NodeEnum::AExpr(e)) => e
+            .lexpr
+            .as_deref()
+            .into_iter()
+            .chain(e.rexpr.as_deref())
+            .collect(),
+        Some(NodeEnum::BoolExpr(e)) => e.args.iter().collect(),
+        Some(NodeEnum::FuncCall(f)) => f.args.iter().collect(),
+        Some(NodeEnum::CoalesceExpr(e)) => e.args.iter().collect(),
+        Some(NodeEnum::AArrayExpr(a)) => a.elements.iter().collect(),
+        Some(NodeEnum::List(l)) => l.items.iter().collect(),
+        Some(NodeEnum::TypeCast(c)) => c.arg.as_deref().into_iter().collect(),
+        Some(NodeEnum::NullTest(t)) => t.arg.as_deref().into_iter().collect(),
+        Some(NodeEnum::BooleanTest(t)) => t.arg.as_deref().into_iter().collect(),
+        Some(NodeEnum::CaseExpr(c)) => c
+            .arg
+            .as_deref()
+            .into_iter()
+            .chain(c.args.iter())
+            .chain(c.defresult.as_deref())
+            .collect(),
+        Some(NodeEnum::CaseWhen(w)) => w
+            .expr
+            .as_deref()
+            .into_iter()
+            .chain(w.result.as_deref())
+            .collect(),
+        _ => vec![],
+    };
+    for child in children {
+        column_refs(child, columns);
+    }
+}
+
+fn constraints_of(node: &Node) -> Vec<&Constraint> {
+    match &node.node {
+        Some(NodeEnum::ColumnDef(column)) => column
+            .constraints
+            .iter()
+            .filter_map(|n| match &n.node {
+                Some(NodeEnum::Constraint(c)) => Some(c.as_ref()),
+                _ => None,
+            })
+            .collect(),
+        Some(NodeEnum::Constraint(c)) => vec![c.as_ref()],
+        _ => vec![],
+    }
+}
+
+fn into_constraint(node: Node) -> Option<Constraint> {
+    match node.node {
+        Some(NodeEnum::Constraint(c)) => Some(*c),
+        _ => None,
+    }
+}
+
+fn constraint_node(constraint: Constraint) -> Node {
+    Node {
+        node: Some(NodeEnum::Constraint(Box::new(constraint))),
+    }
+}
+
+fn names(nodes: &[Node]) -> Vec<String> {
+    nodes.iter().filter_map(node_to_string).collect()
+}
+
+fn quote_literal(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
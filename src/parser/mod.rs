@@ -5,7 +5,7 @@ mod mview;
 mod privilege;
 mod sequence;
 mod table;
-mod utils;
+pub(crate) mod utils;
 mod view;
 
 use derivative::Derivative;
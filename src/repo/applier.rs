@@ -1,21 +1,46 @@
-use std::thread;
+use std::{thread, time::Instant};
 
-use crate::{utils::load_config, DatabaseRepo, DatabaseSchema, SchemaLoader, SqlSaver};
+use super::{
+    history::{finish_migration, record_migration, PendingMigration},
+    hooks::{create_hooks_table, record_hook},
+    ShadowError, RENOVATE_SCHEMA,
+};
+use crate::{
+    connection::{mask_url, strip_password, url_password},
+    utils::load_config,
+    ApplyLock, ApplyReport, DatabaseRepo, DatabaseSchema, MigrationPhase, MigrationStep, SavedPlan,
+    SchemaLoader, SqlLoader, SqlSaver, StepFailure,
+};
 use anyhow::{bail, Result};
 use sqlx::{Connection, Executor, PgConnection};
 use tokio::runtime::Runtime;
 use url::Url;
 use uuid::Uuid;
 
+/// undo the session level timeouts set for the non-transactional statements
+const RESET_TIMEOUTS: &str = "RESET lock_timeout; RESET statement_timeout;";
+
 impl DatabaseRepo {
     pub async fn load_sql_string(&self, remote: bool) -> Result<String> {
+        self.dump_schema(remote, &[]).await
+    }
+
+    /// run `pg_dump -s` on the local or remote database with the extra arguments
+    pub(super) async fn dump_schema(&self, remote: bool, args: &[&str]) -> Result<String> {
         let url = if remote { &self.remote_url } else { &self.url };
 
-        let output = async_process::Command::new("pg_dump")
-            .arg("-s")
-            .arg(url)
-            .output()
-            .await?;
+        let mut cmd = async_process::Command::new("pg_dump");
+        cmd.arg("-s")
+            .arg("-N")
+            .arg(RENOVATE_SCHEMA)
+            .args(self.filter.pg_dump_args())
+            .args(args)
+            // the password is passed via env, so that it doesn't show up in `ps`
+            .arg(strip_password(url));
+        if let Some(password) = url_password(url) {
+            cmd.env("PGPASSWORD", password);
+        }
+        let output = cmd.output().await?;
 
         if !output.status.success() {
             bail!("{}", String::from_utf8(output.stderr)?);
@@ -24,20 +49,45 @@ impl DatabaseRepo {
         let sql = String::from_utf8(output.stdout)?;
         Ok(sql)
     }
+
+    /// load the schema of the local or remote database, without the objects excluded by the
+    /// filter rules
+    pub async fn load_schema(&self, remote: bool) -> Result<DatabaseSchema> {
+        let sql = self.load_sql_string(remote).await?;
+        let mut schema = SqlLoader::new(sql).load().await?;
+        self.filter.apply(&mut schema);
+        Ok(schema)
+    }
+
     pub async fn normalize(&self, sql: &str) -> Result<DatabaseSchema> {
-        let tdb = TmpDb::new(self.server_url()?, sql).await?;
+        let tdb = TmpDb::new(self.shadow_server_url()?, sql).await?;
         let repo = DatabaseRepo::new_with(tdb.url());
         repo.load().await
     }
 
-    /// Apply the migration plan to the remote database server.
-    pub async fn apply(&self, plan: Vec<String>, remote: bool) -> Result<()> {
-        if !remote {
-            self.do_apply(&plan, &self.url).await?;
-        } else if self.url != self.remote_url {
-            self.do_apply(&plan, &self.remote_url).await?;
+    /// Apply the migration plan to the remote database server, and record it in the migration
+    /// history of the database.
+    pub async fn apply(&self, saved: &SavedPlan, commit_id: Option<&str>) -> Result<()> {
+        let mut report = ApplyReport::new(&saved.plan, "");
+        let lock = self.lock_apply(saved.remote).await?;
+        self.apply_with_report(saved, commit_id, lock, &mut report)
+            .await
+    }
+
+    /// Apply the migration plan like `apply` on the connection holding the lock taken by
+    /// `lock_apply`, and record what happened to each statement in the report, even if the
+    /// plan fails. The lock is released once the migration is recorded.
+    pub async fn apply_with_report(
+        &self,
+        saved: &SavedPlan,
+        commit_id: Option<&str>,
+        lock: ApplyLock,
+        report: &mut ApplyReport,
+    ) -> Result<()> {
+        if saved.remote && self.url == self.remote_url {
+            return lock.release().await;
         }
-        Ok(())
+        self.do_apply(saved, lock, commit_id, report).await
     }
 
     /// Fetch the most recent schema from the remote database server.
@@ -54,7 +104,7 @@ impl DatabaseRepo {
         match ret {
             Ok(_) => Ok(()),
             Err(_) => {
-                let server_url = self.server_url()?;
+                let server_url = self.local_server_url()?;
                 let sql = if self.url != self.remote_url {
                     self.load_sql_string(true).await?
                 } else {
@@ -69,23 +119,174 @@ impl DatabaseRepo {
 
     /// drop database
     pub async fn drop_database(&self) -> Result<()> {
-        drop_database(&self.server_url()?, &self.db_name()?).await
+        drop_database(&self.local_server_url()?, &self.db_name()?).await
     }
 
-    async fn do_apply(&self, plan: &[String], url: &str) -> Result<()> {
-        let mut conn = PgConnection::connect(url).await?;
-        let mut tx = conn.begin().await?;
+    async fn do_apply(
+        &self,
+        saved: &SavedPlan,
+        mut lock: ApplyLock,
+        commit_id: Option<&str>,
+        report: &mut ApplyReport,
+    ) -> Result<()> {
+        let url = lock.url.clone();
+        report.target = mask_url(&url);
+        let remote = url != self.url;
+        // the lock is held until the migration is recorded, so that concurrent applies don't
+        // interleave
+        let conn = &mut lock.conn;
 
-        for sql in plan {
-            tx.execute(sql.as_str()).await?;
+        // another apply may have run since the plan was made. The post-deploy phase is planned
+        // against the schema before the pre-deploy one, which is checked by the caller
+        let before = self.load_schema(remote).await?;
+        if saved.phase != Some(MigrationPhase::Post) {
+            saved.check_drift(&before)?;
         }
-        tx.commit().await?;
+        let pending = PendingMigration {
+            saved,
+            commit_id,
+            start: Instant::now(),
+        };
+        let ret = self.run_plan(conn, saved, report, Some(&pending)).await;
+        let duration = pending.start.elapsed();
+        report.finish(duration, ret.is_ok());
+        let id = ret?;
 
-        self.fetch().await?;
-        Ok(())
+        // the plan is committed and recorded. If the schema after it can't be loaded, the
+        // fingerprint is left empty as it's unknown
+        let after = self.load_schema(remote).await;
+        let rollback = match (saved.phase, &after) {
+            // the rollback of a phase only reverts the part of the plan applied
+            (Some(_), Ok(schema)) => {
+                let mut rollback = before.rollback(schema)?;
+                rollback.flag_lossy(&saved.plan);
+                Some(rollback)
+            }
+            _ => None,
+        };
+        let fingerprint = after
+            .as_ref()
+            .map(|schema| schema.fingerprint())
+            .unwrap_or_default();
+        if let Some(id) = id {
+            finish_migration(conn, id, duration, &fingerprint, rollback.as_ref()).await?;
+        }
+        after?;
+
+        // the local repo tracks the local database, so it's only updated if that one is changed
+        if url == self.url {
+            self.fetch().await?;
+        }
+        lock.release().await
+    }
+
+    /// run the batches of the plan. The pending migration is recorded in the transaction of the
+    /// last batch, or on its own after the plan if that batch is not transactional. Returns
+    /// the id of the record
+    pub(super) async fn run_plan(
+        &self,
+        conn: &mut PgConnection,
+        saved: &SavedPlan,
+        report: &mut ApplyReport,
+        pending: Option<&PendingMigration<'_>>,
+    ) -> Result<Option<i64>> {
+        // statements like `CREATE INDEX CONCURRENTLY` can't run inside a transaction block,
+        // so they're executed on their own between the transactional batches
+        if saved.plan.steps.iter().any(|step| step.hook.is_some()) {
+            create_hooks_table(conn).await?;
+        }
+        let batches = saved.plan.batches();
+        let mut id = None;
+        let mut offset = 0;
+        for (n, batch) in batches.iter().enumerate() {
+            if batch.iter().all(|step| step.transactional) {
+                let pending = pending.filter(|_| n + 1 == batches.len());
+                id = self
+                    .apply_batch(conn, batch, offset, report, pending)
+                    .await?;
+            } else {
+                conn.execute(self.apply.timeouts_sql(false)?.as_str())
+                    .await?;
+                let mut failure = None;
+                for (i, step) in batch.iter().enumerate() {
+                    // a failed `CREATE INDEX CONCURRENTLY` leaves an invalid index behind, so
+                    // it's not retried
+                    if let Err(e) = run_step(conn, step, offset + i, report).await {
+                        failure = Some(StepFailure::new(Some(offset + i), &step.sql, &e, false, 1));
+                        break;
+                    }
+                }
+                // the timeouts are set for the session, and would otherwise apply to what runs
+                // after the batch on this connection, e.g. recording the history
+                conn.execute(RESET_TIMEOUTS).await?;
+                if let Some(failure) = failure {
+                    return Err(report.fail(failure));
+                }
+            }
+            offset += batch.len();
+        }
+        match (pending, id) {
+            (Some(pending), None) => {
+                let mut tx = conn.begin().await?;
+                let id = record_migration(&mut tx, pending).await?;
+                tx.commit().await?;
+                Ok(Some(id))
+            }
+            (_, id) => Ok(id),
+        }
     }
 
-    fn server_url(&self) -> Result<String> {
+    /// run the transactional batch with the timeouts set. It's retried with backoff if a
+    /// statement times out on a lock, and the transaction is rolled back before each retry
+    async fn apply_batch(
+        &self,
+        conn: &mut PgConnection,
+        batch: &[MigrationStep],
+        offset: usize,
+        report: &mut ApplyReport,
+        pending: Option<&PendingMigration<'_>>,
+    ) -> Result<Option<i64>> {
+        let timeouts = self.apply.timeouts_sql(true)?;
+        let mut attempt = 1;
+        loop {
+            let ret = run_batch(conn, batch, offset, &timeouts, report, pending).await;
+            let (index, sql, e) = match ret {
+                Ok(id) => return Ok(id),
+                Err(e) => e,
+            };
+            report.rolled_back(offset..offset + batch.len());
+            if !is_lock_timeout(&e) || attempt > self.apply.retries {
+                let failure = StepFailure::new(index, sql, &e, true, attempt);
+                return Err(report.fail(failure));
+            }
+            let backoff = self.apply.backoff(attempt)?;
+            eprintln!(
+                "Timed out waiting for a lock on attempt {}/{}: {}\nRetrying in {:?}...",
+                attempt,
+                self.apply.retries + 1,
+                sql,
+                backoff
+            );
+            tokio::time::sleep(backoff).await;
+            attempt += 1;
+        }
+    }
+
+    /// the server to create the local database on. It's the shadow server, which may have a
+    /// role allowed to create databases, unless the local url is on another server
+    pub(super) fn local_server_url(&self) -> Result<String> {
+        let local = Url::parse(&self.url)?;
+        let shadow = Url::parse(&self.shadow_server_url()?)?;
+        if (local.host_str(), local.port_or_known_default())
+            == (shadow.host_str(), shadow.port_or_known_default())
+        {
+            Ok(shadow.to_string())
+        } else {
+            self.server_url()
+        }
+    }
+
+    pub(super) fn server_url(&self) -> Result<String> {
         let mut url = Url::parse(&self.url)?;
         url.set_path("");
         Ok(url.to_string())
@@ -99,6 +300,106 @@ impl DatabaseRepo {
     }
 }
 
+/// run the statements in a transaction. On error, the index and the sql of the failed
+/// statement are returned with it, and the transaction is rolled back when dropped
+async fn run_batch(
+    conn: &mut PgConnection,
+    batch: &[MigrationStep],
+    offset: usize,
+    timeouts: &str,
+    report: &mut ApplyReport,
+    pending: Option<&PendingMigration<'_>>,
+) -> Result<Option<i64>, (Option<usize>, String, sqlx::Error)> {
+    let mut tx = conn
+        .begin()
+        .await
+        .map_err(|e| (None, "BEGIN".to_owned(), e))?;
+    if !timeouts.is_empty() {
+        tx.execute(timeouts)
+            .await
+            .map_err(|e| (None, timeouts.to_owned(), e))?;
+    }
+    for (i, step) in batch.iter().enumerate() {
+        run_step(&mut tx, step, offset + i, report)
+            .await
+            .map_err(|e| (Some(offset + i), step.sql.clone(), e))?;
+    }
+    let id = match pending {
+        Some(pending) => Some(
+            record_migration(&mut tx, pending)
+                .await
+                .map_err(|e| (None, "INSERT INTO _renovate.migrations".to_owned(), e))?,
+        ),
+        None => None,
+    };
+    tx.commit()
+        .await
+        .map_err(|e| (None, "COMMIT".to_owned(), e))?;
+    Ok(id)
+}
+
+/// run a step of the plan, and print the progress
+async fn run_step(
+    conn: &mut PgConnection,
+    step: &MigrationStep,
+    index: usize,
+    report: &mut ApplyReport,
+) -> Result<(), sqlx::Error> {
+    let start = Instant::now();
+    let ret = match &step.batch_sql {
+        Some(batch_sql) => run_batches(conn, batch_sql).await,
+        None => conn.execute(step.sql.as_str()).await.map(|_| ()),
+    };
+    let ret = match (ret, &step.hook) {
+        (Ok(()), Some(name)) => record_hook(conn, name).await,
+        (ret, _) => ret,
+    };
+    let elapsed = start.elapsed();
+    report.record_step(index, elapsed, ret.is_ok());
+    if ret.is_ok() {
+        println!(
+            "[{}/{}] {} {} ({} ms)\n    {}",
+            index + 1,
+            report.steps.len(),
+            step.type_name,
+            step.id,
+            elapsed.as_millis(),
+            step.sql
+        );
+    }
+    ret
+}
+
+/// run the statement from the start of the table until it has paged through all of it. Each run
+/// returns the rows it updated and the ctid to continue from, or NULL once no row is left. Outside
+/// of a transaction block, each run is committed on its own, so that the locks and the WAL of a
+/// backfill stay small
+async fn run_batches(conn: &mut PgConnection, sql: &str) -> Result<(), sqlx::Error> {
+    let mut cursor = "(0,0)".to_owned();
+    let mut total = 0;
+    loop {
+        let (rows, last): (i64, Option<String>) = sqlx::query_as(sql)
+            .bind(&cursor)
+            .fetch_one(&mut *conn)
+            .await?;
+        let last = match last {
+            Some(last) => last,
+            None => break,
+        };
+        total += rows;
+        println!("    {} rows updated", total);
+        cursor = last;
+    }
+    Ok(())
+}
+
+/// SQLSTATE 55P03: lock_not_available, raised when `lock_timeout` is exceeded
+fn is_lock_timeout(e: &sqlx::Error) -> bool {
+    e.as_database_error()
+        .and_then(|e| e.code())
+        .map_or(false, |code| code == "55P03")
+}
+
 #[derive(Debug)]
 pub struct TmpDb {
     pub server_url: String,
@@ -108,8 +409,36 @@ pub struct TmpDb {
 impl TmpDb {
     pub async fn new(server_url: String, sql: &str) -> Result<Self> {
         let dbname = format!("tmpdb_{}", Uuid::new_v4());
-        init_database(&server_url, &dbname, sql).await?;
-        Ok(Self { server_url, dbname })
+        let connect_error = |e: sqlx::Error| ShadowError::Connect {
+            server: mask_url(&server_url),
+            reason: e.to_string(),
+        };
+
+        let mut conn = PgConnection::connect(&server_url)
+            .await
+            .map_err(connect_error)?;
+        conn.execute(format!(r#"CREATE DATABASE "{}""#, dbname).as_str())
+            .await
+            .map_err(|e| ShadowError::CreateDatabase {
+                server: mask_url(&server_url),
+                reason: e.to_string(),
+            })?;
+        // the temp database is dropped from now on, even if connecting to it or loading the
+        // schema fails
+        let tdb = Self {
+            server_url: server_url.clone(),
+            dbname,
+        };
+        let mut conn = PgConnection::connect(&tdb.url())
+            .await
+            .map_err(connect_error)?;
+
+        let mut tx = conn.begin().await?;
+        tx.execute(sql).await.map_err(|e| ShadowError::LoadSchema {
+            reason: e.to_string(),
+        })?;
+        tx.commit().await?;
+        Ok(tdb)
     }
 
     pub fn server_url(&self) -> String {
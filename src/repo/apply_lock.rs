@@ -0,0 +1,125 @@
+use crate::{config::parse_duration, ApplyLock, DatabaseRepo};
+use anyhow::{bail, Result};
+use sqlx::{Connection, Executor, PgConnection};
+use std::time::{Duration, Instant};
+
+/// first key of the advisory lock, to tell it from the locks the app takes. The second key is
+/// the hash of the database name
+const LOCK_NAMESPACE: i32 = 0x7265_6e76;
+
+/// how long to wait for another apply if `apply.apply_lock_timeout` is not set
+const DEFAULT_APPLY_LOCK_TIMEOUT: &str = "10min";
+
+/// how often to try to take the lock while another apply holds it
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+const HOLDER: &str = r#"
+SELECT a.pid, coalesce(nullif(a.application_name, ''), 'unknown'), a.usename::text, a.client_addr::text
+FROM pg_catalog.pg_locks l
+JOIN pg_catalog.pg_stat_activity a ON a.pid = l.pid
+WHERE l.locktype = 'advisory' AND l.granted AND l.objsubid = 2
+  AND l.database = (SELECT oid FROM pg_catalog.pg_database WHERE datname = current_database())
+  AND l.classid = $1::int4::oid AND l.objid = hashtext(current_database())::oid
+"#;
+
+impl DatabaseRepo {
+    /// Take the apply lock of the local or remote database, waiting for another apply up to
+    /// `apply.apply_lock_timeout`. It's taken once the plan is confirmed, so the target has to
+    /// be checked again under the lock
+    pub async fn lock_apply(&self, remote: bool) -> Result<ApplyLock> {
+        let url = if remote { &self.remote_url } else { &self.url };
+        let timeout = parse_duration(
+            self.apply
+                .apply_lock_timeout
+                .as_deref()
+                .unwrap_or(DEFAULT_APPLY_LOCK_TIMEOUT),
+        )?;
+        let mut conn = PgConnection::connect(url).await?;
+        acquire_apply_lock(&mut conn, timeout).await?;
+        Ok(ApplyLock {
+            conn,
+            url: url.clone(),
+        })
+    }
+}
+
+impl ApplyLock {
+    pub async fn release(mut self) -> Result<()> {
+        release_apply_lock(&mut self.conn).await?;
+        self.conn.close().await?;
+        Ok(())
+    }
+}
+
+/// Take the session level advisory lock of the database, so that only one apply runs on it at
+/// a time. It waits for the apply holding the lock up to `timeout`.
+/// The lock is released by `release_apply_lock` or when the connection is closed.
+async fn acquire_apply_lock(conn: &mut PgConnection, timeout: Duration) -> Result<()> {
+    // tell the other applies who holds the lock, unless the url names the app already
+    conn.execute(
+        "SELECT set_config('application_name', 'renovate', false) WHERE current_setting('application_name') = ''",
+    )
+    .await?;
+
+    let start = Instant::now();
+    let mut waiting = false;
+    loop {
+        let locked: bool =
+            sqlx::query_scalar("SELECT pg_try_advisory_lock($1, hashtext(current_database()))")
+                .bind(LOCK_NAMESPACE)
+                .fetch_one(&mut *conn)
+                .await?;
+        if locked {
+            return Ok(());
+        }
+
+        let holder = lock_holder(conn).await?;
+        if start.elapsed() >= timeout {
+            bail!(
+                "Timed out after {:?} waiting for another apply on the database, held by {}. Try again once it's done, or increase `--apply-lock-timeout`.",
+                start.elapsed(),
+                holder
+            );
+        }
+        if !waiting {
+            eprintln!(
+                "Waiting for another apply on the database, held by {}...",
+                holder
+            );
+            waiting = true;
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+async fn release_apply_lock(conn: &mut PgConnection) -> Result<()> {
+    sqlx::query("SELECT pg_advisory_unlock($1, hashtext(current_database()))")
+        .bind(LOCK_NAMESPACE)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+/// describe the session holding the lock, e.g. `renovate (pid 42, user deploy from 10.0.0.5)`
+async fn lock_holder(conn: &mut PgConnection) -> Result<String> {
+    let holder: Option<(i32, String, Option<String>, Option<String>)> = sqlx::query_as(HOLDER)
+        .bind(LOCK_NAMESPACE)
+        .fetch_optional(conn)
+        .await?;
+    let s = match holder {
+        Some((pid, app, user, addr)) => {
+            let mut s = format!("{} (pid {}", app, pid);
+            if let Some(user) = user {
+                s.push_str(&format!(", user {}", user));
+            }
+            if let Some(addr) = addr {
+                s.push_str(&format!(" from {}", addr));
+            }
+            s.push(')');
+            s
+        }
+        // the lock was released in between
+        None => "a session which just finished".to_owned(),
+    };
+    Ok(s)
+}
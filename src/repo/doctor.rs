@@ -0,0 +1,33 @@
+use super::applier::TmpDb;
+use crate::DatabaseRepo;
+use anyhow::{bail, Result};
+use sqlx::{Connection, PgConnection};
+
+impl DatabaseRepo {
+    /// check if the database could be connected
+    pub async fn check_connection(&self, remote: bool) -> Result<()> {
+        let url = if remote { &self.remote_url } else { &self.url };
+        let conn = PgConnection::connect(url).await?;
+        conn.close().await?;
+        Ok(())
+    }
+
+    /// check if a temp database could be created and dropped on the shadow server
+    pub async fn check_shadow(&self) -> Result<()> {
+        let tdb = TmpDb::new(self.shadow_server_url()?, "SELECT 1").await?;
+        drop(tdb);
+        Ok(())
+    }
+}
+
+/// version of the `pg_dump` used to dump the schema
+pub async fn pg_dump_version() -> Result<String> {
+    let output = async_process::Command::new("pg_dump")
+        .arg("--version")
+        .output()
+        .await?;
+    if !output.status.success() {
+        bail!("{}", String::from_utf8(output.stderr)?);
+    }
+    Ok(String::from_utf8(output.stdout)?.trim().to_owned())
+}
@@ -0,0 +1,139 @@
+use crate::{config::RenovateFormatConfig, DatabaseSchema, FormattedFiles, LocalRepo, Normalizer};
+use anyhow::{Context, Result};
+use pg_query::protobuf::Token;
+use tokio::fs;
+
+impl LocalRepo {
+    /// rewrite the sql files into the form of pg_dump, see RFC 0002. Returns the files which
+    /// were not formatted. If `check` is true, the files are left untouched. Files with
+    /// comments are never rewritten, since the AST the rewrite works on has no comments, so
+    /// they are returned as commented if their statements are not in that form
+    pub async fn format(
+        &self,
+        format: Option<RenovateFormatConfig>,
+        check: bool,
+    ) -> Result<FormattedFiles> {
+        let mut files = Vec::new();
+        for file in self.files()? {
+            let sql = fs::read_to_string(&file).await?;
+            files.push((file, sql));
+        }
+        // owners are kept as is, since there's no database to tell who the owner is
+        let normalized = Normalizer::default().normalize_files(&files)?;
+
+        let mut ret = FormattedFiles::default();
+        for ((file, sql), normalized) in files.into_iter().zip(normalized) {
+            let context = || format!("Failed to format {}", file.display());
+            if has_comments(&sql).with_context(context)? {
+                if !same_statements(&sql, &normalized).with_context(context)? {
+                    ret.commented.push(file);
+                }
+                continue;
+            }
+            let content = DatabaseSchema::format_sql(&normalized, format);
+            if content != sql {
+                if !check {
+                    fs::write(&file, content).await?;
+                }
+                ret.changed.push(file);
+            }
+        }
+        Ok(ret)
+    }
+}
+
+/// whether the sql has the same statements as the normalized one, regardless of the comments
+/// and the layout
+fn same_statements(sql: &str, normalized: &str) -> Result<bool> {
+    Ok(pg_query::parse(sql)?.deparse()? == pg_query::parse(normalized)?.deparse()?)
+}
+
+fn has_comments(sql: &str) -> Result<bool> {
+    let scanned = pg_query::scan(sql)?;
+    Ok(scanned
+        .tokens
+        .iter()
+        .any(|t| t.token == Token::SqlComment as i32 || t.token == Token::CComment as i32))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn local_repo_format_should_rewrite_files_once() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file = dir.path().join("public/04_tables.sql");
+        fs::create_dir_all(file.parent().unwrap()).await?;
+        fs::write(
+            &file,
+            "CREATE TABLE todos (id serial PRIMARY KEY, title text NOT NULL CHECK (title <> ''));",
+        )
+        .await?;
+
+        let repo = LocalRepo::new(dir.path());
+        let format = Some(RenovateFormatConfig::default());
+        assert_eq!(repo.format(format, true).await?.changed, vec![file.clone()]);
+        assert!(fs::read_to_string(&file)
+            .await?
+            .starts_with("CREATE TABLE todos"));
+
+        assert_eq!(
+            repo.format(format, false).await?.changed,
+            vec![file.clone()]
+        );
+        let content = fs::read_to_string(&file).await?;
+        assert!(content.contains("CONSTRAINT todos_title_check"));
+        assert!(content.contains("CREATE SEQUENCE public.todos_id_seq"));
+        assert!(content.contains("CONSTRAINT todos_pkey PRIMARY KEY (id)"));
+
+        assert_eq!(repo.format(format, true).await?, FormattedFiles::default());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn local_repo_format_should_skip_files_with_comments() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file = dir.path().join("public/04_tables.sql");
+        fs::create_dir_all(file.parent().unwrap()).await?;
+        let sql = "-- the todo items\nCREATE TABLE todos (id serial PRIMARY KEY, /* required */ title text NOT NULL);";
+        fs::write(&file, sql).await?;
+
+        let repo = LocalRepo::new(dir.path());
+        let format = Some(RenovateFormatConfig::default());
+        let ret = repo.format(format, false).await?;
+        assert!(ret.changed.is_empty());
+        assert_eq!(ret.commented, vec![file.clone()]);
+        assert_eq!(fs::read_to_string(&file).await?, sql);
+
+        // a commented file in the form of pg_dump is fine
+        let sql = "-- the todo items\nCREATE TABLE public.todos (title text);";
+        fs::write(&file, sql).await?;
+        assert_eq!(repo.format(format, true).await?, FormattedFiles::default());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn local_repo_format_should_know_types_and_keys_of_other_files() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let types = dir.path().join("public/01_types.sql");
+        let tables = dir.path().join("public/04_tables.sql");
+        fs::create_dir_all(types.parent().unwrap()).await?;
+        fs::write(&types, "CREATE TYPE public.status AS ENUM ('todo', 'done');\nCREATE TABLE public.users (id integer PRIMARY KEY);").await?;
+        fs::write(
+            &tables,
+            "CREATE TABLE public.todos (status status, user_id integer REFERENCES public.users);",
+        )
+        .await?;
+
+        let repo = LocalRepo::new(dir.path());
+        repo.format(Some(RenovateFormatConfig::default()), false)
+            .await?;
+        let content = fs::read_to_string(&tables).await?;
+        assert!(content.contains("status public.status"));
+        // the referenced columns come from the primary key in the other file
+        assert!(content.contains("REFERENCES public.users"));
+        assert!(content.contains("(id)"));
+        Ok(())
+    }
+}
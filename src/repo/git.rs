@@ -1,6 +1,10 @@
 #![allow(clippy::unwrap_used)]
-use git2::{Error, IndexAddOption, Object, ObjectType, Oid, Repository, Signature};
+use git2::{
+    Error, IndexAddOption, Object, ObjectType, Oid, Repository, Signature, TreeWalkMode,
+    TreeWalkResult,
+};
 use std::{
+    collections::BTreeMap,
     env, fmt, fs,
     path::{Path, PathBuf},
     sync::Arc,
@@ -115,6 +119,45 @@ impl GitRepo {
         Ok(old_ref)
     }
 
+    /// read all the files under the directory at the given revision from git objects, without
+    /// touching the working tree. The paths are relative to the directory
+    pub fn read_files(
+        &self,
+        refname: &str,
+        dir: impl AsRef<Path>,
+    ) -> Result<BTreeMap<PathBuf, Vec<u8>>, Error> {
+        let dir = dir.as_ref();
+        let mut tree = self.0.revparse_single(refname)?.peel_to_tree()?;
+        if !dir.as_os_str().is_empty() {
+            tree = tree.get_path(dir)?.to_object(&self.0)?.peel_to_tree()?;
+        }
+
+        let mut files = BTreeMap::new();
+        let mut error = None;
+        let ret = tree.walk(TreeWalkMode::PreOrder, |root, entry| {
+            if entry.kind() != Some(ObjectType::Blob) {
+                return TreeWalkResult::Ok;
+            }
+            let name = String::from_utf8_lossy(entry.name_bytes()).to_string();
+            match entry.to_object(&self.0).and_then(|o| o.peel_to_blob()) {
+                Ok(blob) => {
+                    files.insert(Path::new(root).join(name), blob.content().to_vec());
+                    TreeWalkResult::Ok
+                }
+                Err(e) => {
+                    error = Some(e);
+                    TreeWalkResult::Abort
+                }
+            }
+        });
+        if let Some(e) = error {
+            return Err(e);
+        }
+        ret?;
+
+        Ok(files)
+    }
+
     pub fn find_last_commit(&self) -> Result<Object, Error> {
         self.0.head()?.resolve()?.peel(ObjectType::Commit)
     }
@@ -179,6 +222,8 @@ mod tests {
         fs::write(root.join("file.txt"), "Hello Tyr").await.unwrap();
         repo.commit("2nd commit").unwrap();
         repo.tag("v2.0.0", "2nd tag").unwrap();
+        let files = repo.read_files("v1.0.0", "").unwrap();
+        assert_eq!(files[Path::new("file.txt")], b"Hello World");
         let old_ref = repo.checkout("v1.0.0").unwrap();
         assert_eq!(old_ref, "master");
         repo.checkout(&old_ref).unwrap();
@@ -0,0 +1,101 @@
+use crate::{DatabaseRepo, MigrationPlan, MigrationRecord, SavedPlan};
+use anyhow::Result;
+use sqlx::{types::Json, Connection, Executor, PgConnection};
+use std::time::{Duration, Instant};
+
+/// schema for the bookkeeping tables of renovate. It is excluded when dumping the schema
+pub(crate) const RENOVATE_SCHEMA: &str = "_renovate";
+
+const CREATE_MIGRATIONS_TABLE: &str = r#"
+CREATE SCHEMA IF NOT EXISTS _renovate;
+CREATE TABLE IF NOT EXISTS _renovate.migrations (
+    id bigserial PRIMARY KEY,
+    plan_id text NOT NULL,
+    statements text[] NOT NULL,
+    commit_id text,
+    applied_at timestamptz NOT NULL DEFAULT now(),
+    duration_ms bigint NOT NULL,
+    applied_by text NOT NULL DEFAULT current_user,
+    fingerprint text NOT NULL,
+    rollback jsonb NOT NULL
+);
+ALTER TABLE _renovate.migrations ADD COLUMN IF NOT EXISTS phase text;
+"#;
+
+impl DatabaseRepo {
+    /// list the migrations applied to the database, most recent first
+    pub async fn history(&self, remote: bool) -> Result<Vec<MigrationRecord>> {
+        let url = if remote { &self.remote_url } else { &self.url };
+        let mut conn = PgConnection::connect(url).await?;
+        let exists: bool =
+            sqlx::query_scalar("SELECT to_regclass('_renovate.migrations') IS NOT NULL")
+                .fetch_one(&mut conn)
+                .await?;
+        if !exists {
+            return Ok(vec![]);
+        }
+
+        let records = sqlx::query_as(
+            "SELECT id, plan_id, statements, commit_id, applied_at::text, duration_ms, applied_by, fingerprint, rollback, phase FROM _renovate.migrations ORDER BY id DESC",
+        )
+        .fetch_all(&mut conn)
+        .await?;
+        Ok(records)
+    }
+}
+
+/// the migration `run_plan` records in `_renovate.migrations`, in the transaction of the last
+/// batch, so that it's recorded if and only if the plan is committed
+pub(super) struct PendingMigration<'a> {
+    pub saved: &'a SavedPlan,
+    pub commit_id: Option<&'a str>,
+    pub start: Instant,
+}
+
+/// record the applied plan and its rollback in `_renovate.migrations`, the table is created if
+/// not exists. It runs in the transaction of the caller. The fingerprint and the rollback of a
+/// phase depend on the schema after the plan, so they are filled in by `finish_migration`.
+/// Returns the id of the record
+pub(super) async fn record_migration(
+    conn: &mut PgConnection,
+    pending: &PendingMigration<'_>,
+) -> Result<i64, sqlx::Error> {
+    let saved = pending.saved;
+    let rollback = match saved.phase {
+        None => saved.rollback.clone(),
+        Some(_) => MigrationPlan::default(),
+    };
+    conn.execute(CREATE_MIGRATIONS_TABLE).await?;
+    sqlx::query_scalar(
+        "INSERT INTO _renovate.migrations (plan_id, statements, commit_id, duration_ms, fingerprint, rollback, phase) VALUES ($1, $2, $3, $4, '', $5, $6) RETURNING id",
+    )
+    .bind(saved.plan.id())
+    .bind(saved.plan.sqls())
+    .bind(pending.commit_id)
+    .bind(pending.start.elapsed().as_millis() as i64)
+    .bind(Json(rollback))
+    .bind(saved.phase.map(|phase| phase.to_string()))
+    .fetch_one(conn)
+    .await
+}
+
+/// fill in the duration of the whole plan, the fingerprint of the schema after it, and the
+/// rollback of a phase, once the plan is committed
+pub(super) async fn finish_migration(
+    conn: &mut PgConnection,
+    id: i64,
+    duration: Duration,
+    fingerprint: &str,
+    rollback: Option<&MigrationPlan>,
+) -> Result<()> {
+    sqlx::query(
+        "UPDATE _renovate.migrations SET duration_ms = $2, fingerprint = $3, rollback = coalesce($4, rollback) WHERE id = $1",
+    )
+    .bind(id)
+    .bind(duration.as_millis() as i64)
+    .bind(fingerprint)
+    .bind(rollback.map(Json))
+    .execute(conn)
+    .await?;
+    Ok(())
+}
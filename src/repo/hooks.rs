@@ -0,0 +1,69 @@
+use crate::{DatabaseRepo, HookKind, LocalRepo, MigrationHook};
+use anyhow::{Context, Result};
+use glob::glob;
+use sqlx::{Connection, Executor, PgConnection};
+use tokio::fs;
+
+/// directory of the hook scripts in the local repo. It's skipped when loading the schema, as
+/// it starts with `_`
+const HOOKS_DIR: &str = "_hooks";
+
+impl LocalRepo {
+    /// load the scripts in `_hooks/pre` and `_hooks/post`, sorted by name
+    pub async fn hooks(&self) -> Result<Vec<MigrationHook>> {
+        let dir = self.path.join(HOOKS_DIR);
+        let mut hooks = Vec::new();
+        for (kind, sub) in [(HookKind::Pre, "pre"), (HookKind::Post, "post")] {
+            let glob_path = dir.join(sub).join("*.sql");
+            let mut files = glob(glob_path.as_os_str().to_str().unwrap())?
+                .filter_map(Result::ok)
+                .collect::<Vec<_>>();
+            files.sort();
+            for file in files {
+                let sql = fs::read_to_string(&file)
+                    .await
+                    .with_context(|| format!("Failed to read file: {:?}", file))?;
+                let name = file.strip_prefix(&dir)?.to_string_lossy().into_owned();
+                hooks.push(MigrationHook::new(name, kind, sql)?);
+            }
+        }
+        Ok(hooks)
+    }
+}
+
+impl DatabaseRepo {
+    /// names of the hooks already applied to the database
+    pub async fn consumed_hooks(&self, remote: bool) -> Result<Vec<String>> {
+        let url = if remote { &self.remote_url } else { &self.url };
+        let mut conn = PgConnection::connect(url).await?;
+        let exists: bool = sqlx::query_scalar("SELECT to_regclass('_renovate.hooks') IS NOT NULL")
+            .fetch_one(&mut conn)
+            .await?;
+        if !exists {
+            return Ok(vec![]);
+        }
+
+        let names = sqlx::query_scalar("SELECT name FROM _renovate.hooks ORDER BY name")
+            .fetch_all(&mut conn)
+            .await?;
+        Ok(names)
+    }
+}
+
+/// create the table the applied hooks are recorded in, if not exists
+pub(super) async fn create_hooks_table(conn: &mut PgConnection) -> Result<()> {
+    for sql in MigrationHook::CREATE_TABLE {
+        conn.execute(sql).await?;
+    }
+    Ok(())
+}
+
+/// record the hook as applied. It's run in the transaction of the hook, so that the hook is
+/// recorded if and only if it's committed
+pub(super) async fn record_hook(conn: &mut PgConnection, name: &str) -> Result<(), sqlx::Error> {
+    sqlx::query("INSERT INTO _renovate.hooks (name) VALUES ($1)")
+        .bind(name)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
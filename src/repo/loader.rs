@@ -6,7 +6,7 @@ use crate::{
         TableSequence, Trigger, View,
     },
     utils::ignore_file,
-    DatabaseRepo, DatabaseSchema, LocalRepo, SchemaLoader, SqlLoader,
+    DatabaseRepo, DatabaseSchema, GitRepo, GitRevision, LocalRepo, SchemaLoader, SqlLoader,
 };
 use anyhow::{Context, Result};
 use async_trait::async_trait;
@@ -41,12 +41,46 @@ impl SchemaLoader for LocalRepo {
     }
 }
 
+#[async_trait]
+impl SchemaLoader for GitRevision {
+    async fn load(&self) -> Result<DatabaseSchema> {
+        let sql = self.load_sql().await?;
+        SqlLoader(sql).load().await
+    }
+
+    async fn load_sql(&self) -> Result<String> {
+        let repo = GitRepo::open(&self.path)?;
+        let path = self.path.canonicalize()?;
+        let root = repo.get_root_path().canonicalize()?;
+        let dir = path
+            .strip_prefix(&root)
+            .with_context(|| format!("{:?} is not in the git repo", self.path))?;
+        let files = repo
+            .read_files(&self.revision, dir)
+            .with_context(|| format!("Failed to read files at revision {}", self.revision))?;
+
+        // files are sorted by path, same as `LocalRepo::files`
+        let mut sql = String::with_capacity(16 * 1024);
+        for (file, content) in files {
+            let is_sql = file.extension().map_or(false, |ext| ext == "sql");
+            if is_sql && ignore_file(&file, "_") {
+                sql.push_str(&String::from_utf8(content)?);
+            }
+        }
+
+        let ret = pg_query::parse(&sql)?;
+        let sql = ret.deparse()?;
+        Ok(sql)
+    }
+}
+
 #[async_trait]
 impl SchemaLoader for DatabaseRepo {
     /// run pg_dump us async process and get the output sql
     async fn load(&self) -> anyhow::Result<crate::DatabaseSchema> {
-        let sql = self.load_sql().await?;
-        SqlLoader(sql).load().await
+        #[cfg(feature = "cli-test")]
+        self.init_local_database().await?;
+        self.load_schema(false).await
     }
 
     async fn load_sql(&self) -> anyhow::Result<String> {
@@ -181,3 +215,38 @@ impl LocalRepo {
         Ok(files)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn git_revision_should_load_committed_sql_files() -> Result<()> {
+        let root = tempfile::tempdir()?;
+        let root = root.path();
+        let repo = GitRepo::init(root)?;
+        fs::create_dir_all(root.join("public")).await?;
+        fs::write(
+            root.join("public/04_tables.sql"),
+            "CREATE TABLE public.todos (title text);",
+        )
+        .await?;
+        fs::write(
+            root.join("_hidden.sql"),
+            "CREATE TABLE public.hidden (id int);",
+        )
+        .await?;
+        repo.commit("Initial commit")?;
+        fs::write(
+            root.join("public/04_tables.sql"),
+            "CREATE TABLE public.todos (title text, completed boolean);",
+        )
+        .await?;
+
+        let schema = GitRevision::new(root, "HEAD").load().await?;
+        assert_eq!(schema.tables.len(), 1);
+        let table = &schema.tables["public"]["todos"];
+        assert_eq!(table.columns.len(), 1);
+        Ok(())
+    }
+}
@@ -1,29 +1,61 @@
 mod applier;
+mod apply_lock;
+mod doctor;
+mod formatter;
 pub mod git;
+mod history;
+mod hooks;
 mod loader;
+mod preflight;
 mod saver;
+mod shadow;
+mod source;
+mod verifier;
 
-use crate::{DatabaseRepo, LocalRepo, RenovateConfig, SqlLoader};
+use crate::{
+    connection::resolve_url, DatabaseRepo, GitRevision, LocalRepo, RenovateApplyConfig,
+    RenovateConfig, SchemaFilter, SqlLoader,
+};
 use std::path::PathBuf;
 
+pub use doctor::pg_dump_version;
+pub(crate) use history::RENOVATE_SCHEMA;
+pub use shadow::ShadowError;
+
 impl LocalRepo {
     pub fn new(path: impl Into<PathBuf>) -> Self {
         Self { path: path.into() }
     }
 }
 
+impl GitRevision {
+    pub fn new(path: impl Into<PathBuf>, revision: impl Into<String>) -> Self {
+        Self {
+            path: path.into(),
+            revision: revision.into(),
+        }
+    }
+}
+
 impl DatabaseRepo {
     pub fn new(config: &RenovateConfig) -> Self {
         Self {
-            url: config.url.clone(),
-            remote_url: config.remote_url.clone(),
+            url: resolve_url(&config.url),
+            remote_url: resolve_url(&config.remote_url),
+            shadow_url: config.shadow_url.as_deref().map(resolve_url),
+            filter: config.filter(),
+            apply: config.apply.clone(),
         }
     }
 
     pub fn new_with(url: String) -> Self {
+        let url = resolve_url(&url);
         Self {
             url: url.clone(),
             remote_url: url,
+            shadow_url: None,
+            filter: SchemaFilter::default(),
+            apply: RenovateApplyConfig::default(),
         }
     }
 }
@@ -0,0 +1,98 @@
+use crate::{
+    config::parse_duration, DatabaseRepo, PreflightCheck, PreflightQuery, PreflightResult,
+    SavedPlan,
+};
+use anyhow::Result;
+use sqlx::{Connection, Executor, PgConnection};
+
+/// the table columns of a type, quoted
+const TYPE_COLUMNS: &str = r#"
+SELECT a.attrelid::regclass::text, quote_ident(a.attname)
+FROM pg_catalog.pg_attribute a
+JOIN pg_catalog.pg_class c ON c.oid = a.attrelid
+WHERE a.atttypid = to_regtype($1) AND a.attnum > 0 AND NOT a.attisdropped
+  AND c.relkind IN ('r', 'p')
+"#;
+
+impl DatabaseRepo {
+    /// Run the preflight checks of the plan against the target database. Each check runs in a
+    /// read-only transaction of its own, limited by `apply.statement_timeout`, so a check which
+    /// can't run or times out doesn't stop the others. The database is not connected if the
+    /// plan has nothing to check.
+    pub async fn preflight(&self, saved: &SavedPlan) -> Result<Vec<PreflightResult>> {
+        let sqls: Vec<String> = saved.plan.steps.iter().map(|s| s.sql.clone()).collect();
+        let checks = PreflightCheck::analyze_all(&sqls)?;
+        if checks.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let url = if saved.remote {
+            &self.remote_url
+        } else {
+            &self.url
+        };
+        let timeout = self
+            .apply
+            .statement_timeout
+            .as_deref()
+            .map(parse_duration)
+            .transpose()?;
+        let mut conn = PgConnection::connect(url).await?;
+        let mut results = Vec::with_capacity(checks.len());
+        for check in checks {
+            let mut tx = conn.begin().await?;
+            tx.execute("SET TRANSACTION READ ONLY").await?;
+            if let Some(timeout) = timeout {
+                let sql = format!("SET LOCAL statement_timeout = {}", timeout.as_millis());
+                tx.execute(sql.as_str()).await?;
+            }
+            let ret = run_check(&mut tx, &check.query).await;
+            tx.rollback().await?;
+            let (count, error, sql_state) = match ret {
+                Ok(count) => (Some(count), None, None),
+                Err(e) => {
+                    let sql_state = e
+                        .as_database_error()
+                        .and_then(|e| e.code())
+                        .map(|code| code.into_owned());
+                    (None, Some(e.to_string()), sql_state)
+                }
+            };
+            results.push(PreflightResult {
+                check,
+                count,
+                error,
+                sql_state,
+            });
+        }
+        conn.close().await?;
+        Ok(results)
+    }
+}
+
+async fn run_check(conn: &mut PgConnection, query: &PreflightQuery) -> Result<i64, sqlx::Error> {
+    match query {
+        PreflightQuery::Count(sql) | PreflightQuery::Cast(sql) => {
+            sqlx::query_scalar(sql.as_str()).fetch_one(conn).await
+        }
+        PreflightQuery::EnumValues { type_name, values } => {
+            let columns: Vec<(String, String)> = sqlx::query_as(TYPE_COLUMNS)
+                .bind(type_name)
+                .fetch_all(&mut *conn)
+                .await?;
+            let mut count = 0;
+            for (table, column) in columns {
+                let sql = format!(
+                    "SELECT count(*) FROM {} WHERE {}::text <> ALL($1)",
+                    table, column
+                );
+                let n: i64 = sqlx::query_scalar(&sql)
+                    .bind(values.as_slice())
+                    .fetch_one(&mut *conn)
+                    .await?;
+                count += n;
+            }
+            Ok(count)
+        }
+    }
+}
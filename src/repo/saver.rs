@@ -107,17 +107,21 @@ impl DatabaseSchema {
         content: &str,
         format: Option<RenovateFormatConfig>,
     ) -> anyhow::Result<()> {
+        fs::write(filename, Self::format_sql(content, format)).await?;
+        Ok(())
+    }
+
+    /// format the sql the same way as the files written to the local repo
+    pub(crate) fn format_sql(content: &str, format: Option<RenovateFormatConfig>) -> String {
         if let Some(format) = format {
             let content = sqlformat::format(content, &Default::default(), format.into());
             // TODO(hack): sqlformat adds a space before the dollar sign in $$, which is not valid SQL
             let mut content = content.replace("$ $", "$$");
             content.push('\n');
-            fs::write(filename, content).await?;
+            content
         } else {
-            fs::write(filename, content).await?;
-        };
-
-        Ok(())
+            content.to_owned()
+        }
     }
 
     fn table_embedded_resources(&self) -> Vec<BTreeMap<SchemaId, BTreeMap<String, String>>> {
@@ -0,0 +1,108 @@
+use crate::DatabaseRepo;
+use anyhow::Result;
+use std::fmt;
+use url::Url;
+
+/// Errors on the shadow database server, which renovate creates temp databases on. They tell
+/// how to fix the problem instead of a chain of low level errors
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ShadowError {
+    /// can't connect to the shadow server
+    Connect { server: String, reason: String },
+    /// can't create the temp database, e.g. the role has no `CREATEDB` privilege
+    CreateDatabase { server: String, reason: String },
+    /// the local schema can't be loaded into the temp database
+    LoadSchema { reason: String },
+}
+
+impl DatabaseRepo {
+    /// the server to create temp databases on
+    pub fn shadow_server_url(&self) -> Result<String> {
+        match &self.shadow_url {
+            Some(url) => {
+                let mut url = Url::parse(url)?;
+                url.set_path("");
+                Ok(url.to_string())
+            }
+            None => self.server_url(),
+        }
+    }
+}
+
+impl std::error::Error for ShadowError {}
+
+impl fmt::Display for ShadowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShadowError::Connect { server, reason } => write!(
+                f,
+                "can't connect to the shadow database server {}: {}\n\n\
+                Renovate creates temp databases on it to normalize the schema. Make sure the \
+                server is running, or point `shadow_url` in renovate.yml (or the \
+                RENOVATE_SHADOW_URL env var) to a server you can connect to. Run `renovate \
+                schema doctor` to check the setup.",
+                server, reason
+            ),
+            ShadowError::CreateDatabase { server, reason } => write!(
+                f,
+                "can't create a temp database on the shadow database server {}: {}\n\n\
+                Grant the role `CREATEDB` (e.g. `ALTER ROLE <role> CREATEDB`), or point \
+                `shadow_url` in renovate.yml (or the RENOVATE_SHADOW_URL env var) to a server \
+                the role could create databases on.",
+                server, reason
+            ),
+            ShadowError::LoadSchema { reason } => write!(
+                f,
+                "can't load the local schema into the shadow database: {}\n\n\
+                Check the sql files of the local repo. `renovate schema plan` without \
+                `--normalize-with-db` doesn't need the shadow database.",
+                reason
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RenovateConfig;
+
+    #[test]
+    fn shadow_server_url_should_default_to_local_server() -> Result<()> {
+        let mut config = RenovateConfig {
+            url: "postgres://localhost:5432/test".into(),
+            ..Default::default()
+        };
+        assert_eq!(
+            DatabaseRepo::new(&config).shadow_server_url()?,
+            "postgres://localhost:5432"
+        );
+
+        config.shadow_url = Some("postgres://postgres@shadow:5433/postgres".into());
+        assert_eq!(
+            DatabaseRepo::new(&config).shadow_server_url()?,
+            "postgres://postgres@shadow:5433"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn local_database_should_be_created_on_shadow_server() -> Result<()> {
+        let mut config = RenovateConfig {
+            url: "postgres://app@shadow:5433/_renovate_test".into(),
+            shadow_url: Some("postgres://postgres@shadow:5433/postgres".into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            DatabaseRepo::new(&config).local_server_url()?,
+            "postgres://postgres@shadow:5433"
+        );
+
+        config.url = "postgres://app@localhost:5432/test".into();
+        assert_eq!(
+            DatabaseRepo::new(&config).local_server_url()?,
+            "postgres://app@localhost:5432"
+        );
+        Ok(())
+    }
+}
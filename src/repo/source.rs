@@ -0,0 +1,116 @@
+use crate::{
+    connection::mask_url, DatabaseRepo, DatabaseSchema, GitRevision, LocalRepo, Normalizer,
+    RenovateConfig, SchemaLoader, SchemaSource, SqlLoader,
+};
+use anyhow::{bail, Context, Result};
+use std::{fmt, path::PathBuf, str::FromStr};
+use tokio::fs;
+
+impl SchemaSource {
+    /// load the schema. Sources other than a database are normalized the same way as the local
+    /// repo in `renovate schema plan`, so that they could be compared with a database
+    pub async fn load(&self, config: &RenovateConfig) -> Result<DatabaseSchema> {
+        match self {
+            SchemaSource::Url(url) => DatabaseRepo::new_with(url.clone()).load().await,
+            _ => {
+                let sql = self.load_sql(config).await?;
+                let normalizer = Normalizer::with_url(&config.url);
+                SqlLoader::new(normalizer.normalize(&sql)?).load().await
+            }
+        }
+    }
+
+    /// load the sql as is. `git:<ref>` is read from the output path of the renovate repo
+    pub async fn load_sql(&self, config: &RenovateConfig) -> Result<String> {
+        match self {
+            SchemaSource::Url(url) => DatabaseRepo::new_with(url.clone()).load_sql().await,
+            SchemaSource::Dir(path) => LocalRepo::new(path).load_sql().await,
+            SchemaSource::Git(revision) => {
+                GitRevision::new(&config.output.path, revision)
+                    .load_sql()
+                    .await
+            }
+            SchemaSource::File(path) => fs::read_to_string(path)
+                .await
+                .with_context(|| format!("Failed to read file: {:?}", path)),
+        }
+    }
+}
+
+impl FromStr for SchemaSource {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.starts_with("postgres://") || s.starts_with("postgresql://") {
+            return Ok(SchemaSource::Url(s.to_owned()));
+        }
+        if let Some(revision) = s.strip_prefix("git:") {
+            return Ok(SchemaSource::Git(revision.to_owned()));
+        }
+
+        let path = PathBuf::from(s);
+        if path.is_dir() {
+            Ok(SchemaSource::Dir(path))
+        } else if path.is_file() {
+            Ok(SchemaSource::File(path))
+        } else {
+            bail!(
+                "invalid schema source: {}. Expected a postgres url, a directory, a sql file or git:<ref>",
+                s
+            )
+        }
+    }
+}
+
+impl fmt::Display for SchemaSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SchemaSource::Url(url) => write!(f, "{}", mask_url(url)),
+            SchemaSource::Dir(path) | SchemaSource::File(path) => write!(f, "{}", path.display()),
+            SchemaSource::Git(revision) => write!(f, "git:{}", revision),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn schema_source_should_be_parsed() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file = dir.path().join("dump.sql");
+        std::fs::write(&file, "CREATE TABLE public.todos (title text);")?;
+
+        assert_eq!(
+            "postgres://localhost:5432/test".parse::<SchemaSource>()?,
+            SchemaSource::Url("postgres://localhost:5432/test".to_owned())
+        );
+        assert_eq!(
+            "git:main".parse::<SchemaSource>()?,
+            SchemaSource::Git("main".to_owned())
+        );
+        assert_eq!(
+            dir.path().to_str().unwrap().parse::<SchemaSource>()?,
+            SchemaSource::Dir(dir.path().to_path_buf())
+        );
+        assert_eq!(
+            file.to_str().unwrap().parse::<SchemaSource>()?,
+            SchemaSource::File(file.clone())
+        );
+        assert!("not/exists".parse::<SchemaSource>().is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn schema_source_should_load_sql_file() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file = dir.path().join("dump.sql");
+        std::fs::write(&file, "CREATE TABLE public.todos (title text);")?;
+        let schema = SchemaSource::File(file)
+            .load(&RenovateConfig::default())
+            .await?;
+        assert_eq!(schema.tables.len(), 1);
+        Ok(())
+    }
+}
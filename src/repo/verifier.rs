@@ -0,0 +1,59 @@
+use super::applier::TmpDb;
+use crate::{ApplyReport, DatabaseRepo, DatabaseSchema, MigrationPlan, SavedPlan};
+use anyhow::{Context, Result};
+use sqlx::{Connection, PgConnection};
+
+/// the steps left out on the shadow clone, as it's restored without owners and privileges: the
+/// roles they name may not exist on the shadow server
+const SKIPPED_TYPES: [&str; 2] = ["table owner", "privilege"];
+
+impl DatabaseRepo {
+    /// Apply the plan to a shadow clone of the target database, restored from its `pg_dump -s`
+    /// on the shadow server, the same way `apply` runs it. Return the plan from the result to
+    /// the expected schema, which is empty if the plan does everything it should. Owners and
+    /// privileges are not verified.
+    pub async fn verify(
+        &self,
+        saved: &SavedPlan,
+        expected: &DatabaseSchema,
+    ) -> Result<MigrationPlan> {
+        let sql = self
+            .dump_schema(saved.remote, &["--no-owner", "--no-privileges"])
+            .await?;
+        let tdb = TmpDb::new(self.shadow_server_url()?, &sql).await?;
+
+        let steps = saved
+            .plan
+            .steps
+            .iter()
+            .filter(|step| !SKIPPED_TYPES.contains(&step.type_name.as_str()))
+            .cloned()
+            .collect();
+        let shadow = SavedPlan {
+            plan: MigrationPlan { steps },
+            ..saved.clone()
+        };
+        let repo = DatabaseRepo {
+            url: tdb.url(),
+            remote_url: tdb.url(),
+            shadow_url: None,
+            filter: self.filter.clone(),
+            apply: self.apply.clone(),
+        };
+
+        let mut conn = PgConnection::connect(&tdb.url()).await?;
+        let mut report = ApplyReport::new(&shadow.plan, "");
+        repo.run_plan(&mut conn, &shadow, &mut report, None)
+            .await
+            .context("The plan failed on the shadow clone of the target database")?;
+        conn.close().await?;
+
+        let mut result = repo.load_schema(false).await?;
+        let mut expected = expected.clone();
+        for schema in [&mut result, &mut expected] {
+            schema.table_owners.clear();
+            schema.privileges.clear();
+        }
+        expected.plan(&result)
+    }
+}
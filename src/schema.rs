@@ -1,15 +1,31 @@
-use crate::{DatabaseSchema, Differ, MigrationPlanner, NodeDiff, NodeItem};
+use crate::{
+    parser::SchemaId, DatabaseSchema, Differ, MigrationAction, MigrationPlan, MigrationPlanner,
+    MigrationStep, NodeDiff, NodeItem,
+};
 use anyhow::Result;
+use sha2::{Digest, Sha256};
 use std::{
     collections::{BTreeMap, BTreeSet},
     hash::Hash,
     str::FromStr,
 };
 
+/// types of the objects a rollback removes, in the order they are dropped: everything which
+/// depends on an object, e.g. a table using a sequence in a column default, is dropped first
+const DROP_ORDER: [&str; 7] = [
+    "materialized view",
+    "view",
+    "table",
+    "function",
+    "sequence",
+    "composite type",
+    "enum",
+];
+
 trait SchemaPlan {
-    fn diff_altered(&self, remote: &Self, verbose: bool) -> Result<Vec<String>>;
-    fn diff_added(&self, verbose: bool) -> Result<Vec<String>>;
-    fn diff_removed(&self, verbose: bool) -> Result<Vec<String>>;
+    fn diff_altered(&self, remote: &Self) -> Result<Vec<MigrationStep>>;
+    fn diff_added(&self) -> Result<Vec<MigrationStep>>;
+    fn diff_removed(&self) -> Result<Vec<MigrationStep>>;
 }
 
 impl DatabaseSchema {
@@ -37,77 +53,121 @@ impl DatabaseSchema {
         format!("{}{}", sql, self)
     }
 
-    pub fn plan(&self, other: &Self, verbose: bool) -> anyhow::Result<Vec<String>> {
-        let mut migrations: Vec<String> = Vec::new();
+    /// sha256 of the normalized schema. Used to detect if the schema changed since a plan was made
+    pub fn fingerprint(&self) -> String {
+        format!("{:x}", Sha256::digest(self.to_string().as_bytes()))
+    }
+
+    pub fn plan(&self, other: &Self) -> anyhow::Result<MigrationPlan> {
+        let mut migrations: Vec<MigrationStep> = Vec::new();
 
         // add schema names
         migrations.extend(schema_name_added(&self.schemas, &other.schemas)?);
 
         // diff on composite types
-        migrations.extend(schema_diff(
-            &self.composite_types,
-            &other.composite_types,
-            verbose,
-        )?);
-        migrations.extend(schema_diff(&self.enum_types, &other.enum_types, verbose)?);
+        migrations.extend(schema_diff(&self.composite_types, &other.composite_types)?);
+        migrations.extend(schema_diff(&self.enum_types, &other.enum_types)?);
         // diff on sequences
-        migrations.extend(schema_diff(&self.sequences, &other.sequences, verbose)?);
+        migrations.extend(schema_diff(&self.sequences, &other.sequences)?);
         // diff on tables
-        migrations.extend(schema_diff(&self.tables, &other.tables, verbose)?);
+        migrations.extend(schema_diff(&self.tables, &other.tables)?);
 
         // diff on table related stuff
-        migrations.extend(schema_diff(
-            &self.table_sequences,
-            &other.table_sequences,
-            verbose,
-        )?);
+        migrations.extend(schema_diff(&self.table_sequences, &other.table_sequences)?);
         migrations.extend(schema_diff(
             &self.table_constraints,
             &other.table_constraints,
-            verbose,
-        )?);
-        migrations.extend(schema_diff(
-            &self.table_indexes,
-            &other.table_indexes,
-            verbose,
-        )?);
-        migrations.extend(schema_diff(
-            &self.table_policies,
-            &other.table_policies,
-            verbose,
         )?);
+        migrations.extend(schema_diff(&self.table_indexes, &other.table_indexes)?);
+        migrations.extend(schema_diff(&self.table_policies, &other.table_policies)?);
 
         // diff on rls
-        migrations.extend(schema_diff(&self.table_rls, &other.table_rls, verbose)?);
+        migrations.extend(schema_diff(&self.table_rls, &other.table_rls)?);
         // diff on table owners
-        migrations.extend(schema_diff(
-            &self.table_owners,
-            &other.table_owners,
-            verbose,
-        )?);
+        migrations.extend(schema_diff(&self.table_owners, &other.table_owners)?);
 
         // diff on views
-        migrations.extend(schema_diff(&self.views, &other.views, verbose)?);
+        migrations.extend(schema_diff(&self.views, &other.views)?);
         // diff on materialized views
-        migrations.extend(schema_diff(&self.mviews, &other.mviews, verbose)?);
+        migrations.extend(schema_diff(&self.mviews, &other.mviews)?);
         // diff on functions
-        migrations.extend(schema_diff(&self.functions, &other.functions, verbose)?);
+        migrations.extend(schema_diff(&self.functions, &other.functions)?);
 
         // diff on triggers
-        migrations.extend(schema_diff(
-            &self.table_triggers,
-            &other.table_triggers,
-            verbose,
-        )?);
+        migrations.extend(schema_diff(&self.table_triggers, &other.table_triggers)?);
 
         // diff on privileges
-        migrations.extend(schema_diff(&self.privileges, &other.privileges, verbose)?);
+        migrations.extend(schema_diff(&self.privileges, &other.privileges)?);
 
         // finally, drop the schema names
         migrations.extend(schema_name_removed(&self.schemas, &other.schemas)?);
 
-        Ok(migrations)
+        MigrationPlan::new(migrations)
+    }
+
+    /// The plan to revert the migration from `self` to `applied`, to be run right after it.
+    /// Unlike `plan`, the objects the rollback removes are dropped after everything depending
+    /// on them, and the indexes, constraints, owners etc. of the dropped relations get no steps,
+    /// as they go away with them. Schemas are never dropped: the forward plan creates them with
+    /// `IF NOT EXISTS`, so they may have existed before
+    pub fn rollback(&self, applied: &Self) -> Result<MigrationPlan> {
+        let mut applied = applied.clone();
+        for id in applied.removed_relations(self) {
+            applied.table_indexes.remove(&id);
+            applied.table_constraints.remove(&id);
+            applied.table_sequences.remove(&id);
+            applied.table_triggers.remove(&id);
+            applied.table_policies.remove(&id);
+            applied.table_rls.remove(&id);
+            applied.table_owners.remove(&id);
+            applied.privileges.remove(&id.to_string());
+        }
+
+        let plan = self.plan(&applied)?;
+        let mut steps = Vec::with_capacity(plan.len());
+        let mut drops = Vec::new();
+        for object in plan.objects() {
+            let removed = object
+                .steps
+                .iter()
+                .all(|s| s.action == MigrationAction::Drop);
+            match DROP_ORDER.iter().position(|t| *t == object.type_name) {
+                Some(order) if removed => drops.push((order, object.steps)),
+                _ if removed && object.type_name == "schema" => {}
+                _ => steps.extend_from_slice(object.steps),
+            }
+        }
+        // the sort is stable, so objects of the same type keep their order
+        drops.sort_by_key(|(order, _)| *order);
+        for (_, object_steps) in drops {
+            steps.extend_from_slice(object_steps);
+        }
+        MigrationPlan::new(steps)
     }
+
+    /// the tables, sequences, views and materialized views which are not in `other`
+    fn removed_relations(&self, other: &Self) -> Vec<SchemaId> {
+        let mut ids = removed_ids(&self.tables, &other.tables);
+        ids.extend(removed_ids(&self.sequences, &other.sequences));
+        ids.extend(removed_ids(&self.views, &other.views));
+        ids.extend(removed_ids(&self.mviews, &other.mviews));
+        ids
+    }
+}
+
+fn removed_ids<T>(
+    items: &BTreeMap<String, BTreeMap<String, T>>,
+    other: &BTreeMap<String, BTreeMap<String, T>>,
+) -> Vec<SchemaId> {
+    items
+        .iter()
+        .flat_map(|(schema, items)| {
+            items
+                .keys()
+                .filter(move |name| !other.get(schema).map_or(false, |o| o.contains_key(*name)))
+                .map(move |name| SchemaId::new(schema, name))
+        })
+        .collect()
 }
 
 impl<T> SchemaPlan for T
@@ -115,47 +175,23 @@ where
     T: NodeItem + Clone + FromStr<Err = anyhow::Error> + PartialEq + Eq + 'static,
     NodeDiff<T>: MigrationPlanner<Migration = String>,
 {
-    fn diff_altered(&self, remote: &Self, verbose: bool) -> Result<Vec<String>> {
+    fn diff_altered(&self, remote: &Self) -> Result<Vec<MigrationStep>> {
         let diff = remote.diff(self)?;
         if let Some(diff) = diff {
-            if verbose && atty::is(atty::Stream::Stdout) {
-                println!(
-                    "{} {} is changed:\n\n{}",
-                    self.type_name(),
-                    self.id(),
-                    diff.diff
-                );
-            }
-            diff.plan()
+            diff.steps()
         } else {
             Ok(Vec::new())
         }
     }
 
-    fn diff_added(&self, verbose: bool) -> Result<Vec<String>> {
+    fn diff_added(&self) -> Result<Vec<MigrationStep>> {
         let diff = NodeDiff::with_new(self.clone());
-        if verbose && atty::is(atty::Stream::Stdout) {
-            println!(
-                "{} {} is added:\n\n{}",
-                self.type_name(),
-                self.id(),
-                diff.diff,
-            );
-        }
-        diff.plan()
+        diff.steps()
     }
 
-    fn diff_removed(&self, verbose: bool) -> Result<Vec<String>> {
+    fn diff_removed(&self) -> Result<Vec<MigrationStep>> {
         let diff = NodeDiff::with_old(self.clone());
-        if verbose && atty::is(atty::Stream::Stdout) {
-            println!(
-                "{} {} is removed:\n\n{}",
-                self.type_name(),
-                self.id(),
-                diff.diff,
-            );
-        }
-        diff.plan()
+        diff.steps()
     }
 }
 
@@ -164,53 +200,45 @@ where
     T: NodeItem + Clone + FromStr<Err = anyhow::Error> + PartialEq + Eq + 'static,
     NodeDiff<T>: MigrationPlanner<Migration = String>,
 {
-    fn diff_altered(&self, remote: &Self, verbose: bool) -> Result<Vec<String>> {
-        let mut migrations: Vec<String> = Vec::new();
+    fn diff_altered(&self, remote: &Self) -> Result<Vec<MigrationStep>> {
+        let mut migrations: Vec<MigrationStep> = Vec::new();
         let keys: BTreeSet<_> = self.keys().collect();
         let other_keys: BTreeSet<_> = remote.keys().collect();
         let added = keys.difference(&other_keys);
         for key in added {
             let v = self.get(*key).unwrap().clone();
-            let (id, t) = (v.id(), v.type_name());
             let diff = NodeDiff::with_new(v);
-            if verbose && atty::is(atty::Stream::Stdout) {
-                println!("{} {} is added:\n\n{}", t, id, diff.diff);
-            }
-            migrations.extend(diff.plan()?);
+            migrations.extend(diff.steps()?);
         }
         let removed = other_keys.difference(&keys);
         for key in removed {
             let v = remote.get(*key).unwrap().clone();
-            let (id, t) = (v.id(), v.type_name());
             let diff = NodeDiff::with_old(v);
-            if verbose && atty::is(atty::Stream::Stdout) {
-                println!("{} {} is removed:\n\n{}", t, id, diff.diff);
-            }
-            migrations.extend(diff.plan()?);
+            migrations.extend(diff.steps()?);
         }
         let intersection = keys.intersection(&other_keys);
         for key in intersection {
             let local: T = self.get(*key).unwrap().to_string().parse()?;
             let remote: T = remote.get(*key).unwrap().to_string().parse()?;
-            migrations.extend(local.diff_altered(&remote, verbose)?);
+            migrations.extend(local.diff_altered(&remote)?);
         }
 
         Ok(migrations)
     }
 
-    fn diff_added(&self, verbose: bool) -> Result<Vec<String>> {
-        let mut migrations: Vec<String> = Vec::new();
+    fn diff_added(&self) -> Result<Vec<MigrationStep>> {
+        let mut migrations: Vec<MigrationStep> = Vec::new();
         for item in self.values() {
-            migrations.extend(item.diff_added(verbose)?);
+            migrations.extend(item.diff_added()?);
         }
 
         Ok(migrations)
     }
 
-    fn diff_removed(&self, verbose: bool) -> Result<Vec<String>> {
-        let mut migrations: Vec<String> = Vec::new();
+    fn diff_removed(&self) -> Result<Vec<MigrationStep>> {
+        let mut migrations: Vec<MigrationStep> = Vec::new();
         for item in self.values() {
-            migrations.extend(item.diff_removed(verbose)?);
+            migrations.extend(item.diff_removed()?);
         }
         Ok(migrations)
     }
@@ -221,80 +249,88 @@ where
     T: NodeItem + Clone + FromStr<Err = anyhow::Error> + PartialEq + Eq + Ord + Hash + 'static,
     NodeDiff<T>: MigrationPlanner<Migration = String>,
 {
-    fn diff_altered(&self, remote: &Self, verbose: bool) -> Result<Vec<String>> {
-        let mut migrations: Vec<String> = Vec::new();
+    fn diff_altered(&self, remote: &Self) -> Result<Vec<MigrationStep>> {
+        let mut migrations: Vec<MigrationStep> = Vec::new();
         let added = self.difference(remote);
         for v in added {
-            let (id, t) = (v.id(), v.type_name());
             let diff = NodeDiff::with_new(v.clone());
-            if verbose && atty::is(atty::Stream::Stdout) {
-                println!("{} {} is added:\n\n{}", t, id, diff.diff);
-            }
-            migrations.extend(diff.plan()?);
+            migrations.extend(diff.steps()?);
         }
         let removed = remote.difference(self);
         for v in removed {
-            let (id, t) = (v.id(), v.type_name());
             let diff = NodeDiff::with_old(v.clone());
-            if verbose && atty::is(atty::Stream::Stdout) {
-                println!("{} {} is removed:\n\n{}", t, id, diff.diff);
-            }
-            migrations.extend(diff.plan()?);
+            migrations.extend(diff.steps()?);
         }
 
         Ok(migrations)
     }
 
-    fn diff_added(&self, verbose: bool) -> Result<Vec<String>> {
-        let mut migrations: Vec<String> = Vec::new();
+    fn diff_added(&self) -> Result<Vec<MigrationStep>> {
+        let mut migrations: Vec<MigrationStep> = Vec::new();
         for item in self {
-            migrations.extend(item.diff_added(verbose)?);
+            migrations.extend(item.diff_added()?);
         }
 
         Ok(migrations)
     }
 
-    fn diff_removed(&self, verbose: bool) -> Result<Vec<String>> {
-        let mut migrations: Vec<String> = Vec::new();
+    fn diff_removed(&self) -> Result<Vec<MigrationStep>> {
+        let mut migrations: Vec<MigrationStep> = Vec::new();
         for item in self {
-            migrations.extend(item.diff_removed(verbose)?);
+            migrations.extend(item.diff_removed()?);
         }
         Ok(migrations)
     }
 }
 
-fn schema_name_added(local: &BTreeSet<String>, remote: &BTreeSet<String>) -> Result<Vec<String>> {
-    let mut migrations: Vec<String> = Vec::new();
+fn schema_name_added(
+    local: &BTreeSet<String>,
+    remote: &BTreeSet<String>,
+) -> Result<Vec<MigrationStep>> {
+    let mut migrations: Vec<MigrationStep> = Vec::new();
 
     let added = local.difference(remote);
     for key in added {
-        migrations.push(format!("CREATE SCHEMA IF NOT EXISTS {}", key));
+        let sql = format!("CREATE SCHEMA IF NOT EXISTS {}", key);
+        migrations.push(MigrationStep::new(
+            sql,
+            key,
+            "schema",
+            MigrationAction::Create,
+            "",
+        ));
     }
 
     Ok(migrations)
 }
 
-fn schema_name_removed(local: &BTreeSet<String>, remote: &BTreeSet<String>) -> Result<Vec<String>> {
-    let mut migrations: Vec<String> = Vec::new();
+fn schema_name_removed(
+    local: &BTreeSet<String>,
+    remote: &BTreeSet<String>,
+) -> Result<Vec<MigrationStep>> {
+    let mut migrations: Vec<MigrationStep> = Vec::new();
 
     let removed = remote.difference(local);
     for key in removed {
-        migrations.push(format!("DROP SCHEMA {}", key));
+        let sql = format!("DROP SCHEMA {}", key);
+        migrations.push(MigrationStep::new(
+            sql,
+            key,
+            "schema",
+            MigrationAction::Drop,
+            "",
+        ));
     }
 
     Ok(migrations)
 }
 
-fn schema_diff<K, T>(
-    local: &BTreeMap<K, T>,
-    remote: &BTreeMap<K, T>,
-    verbose: bool,
-) -> Result<Vec<String>>
+fn schema_diff<K, T>(local: &BTreeMap<K, T>, remote: &BTreeMap<K, T>) -> Result<Vec<MigrationStep>>
 where
     K: Hash + Eq + Ord,
     T: SchemaPlan,
 {
-    let mut migrations: Vec<String> = Vec::new();
+    let mut migrations: Vec<MigrationStep> = Vec::new();
     let keys: BTreeSet<_> = local.keys().collect();
     let other_keys: BTreeSet<_> = remote.keys().collect();
 
@@ -303,21 +339,21 @@ where
     for key in intersection {
         let local = local.get(*key).unwrap();
         let remote = remote.get(*key).unwrap();
-        migrations.extend(local.diff_altered(remote, verbose)?);
+        migrations.extend(local.diff_altered(remote)?);
     }
 
     // process added
     let added = keys.difference(&other_keys);
     for key in added {
         let local = local.get(*key).unwrap();
-        migrations.extend(local.diff_added(verbose)?);
+        migrations.extend(local.diff_added()?);
     }
 
     // process removed
     let removed = other_keys.difference(&keys);
     for key in removed {
         let remote = remote.get(*key).unwrap();
-        migrations.extend(remote.diff_removed(verbose)?);
+        migrations.extend(remote.diff_removed()?);
     }
     Ok(migrations)
 }
@@ -327,6 +363,7 @@ mod tests {
     use crate::{SchemaLoader, SqlLoader};
 
     use super::*;
+    use crate::test_utils::todos_schema;
 
     #[tokio::test]
     async fn database_schema_plan_should_work() -> Result<()> {
@@ -348,7 +385,8 @@ mod tests {
             "#,
         );
         let local = loader.load().await?;
-        let migrations = local.plan(&remote, false).unwrap();
+        let plan = local.plan(&remote).unwrap();
+        let migrations = plan.sqls();
         assert_eq!(migrations.len(), 4);
         assert_eq!(
             migrations[0],
@@ -366,4 +404,57 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn rollback_should_drop_dependents_first() -> Result<()> {
+        let before = SqlLoader::new("CREATE TABLE public.users (id int)")
+            .load()
+            .await?;
+        let applied = SqlLoader::new(
+            r#"
+            CREATE TABLE public.users (id int);
+            CREATE TYPE public.status AS ENUM ('todo', 'done');
+            CREATE SEQUENCE public.todos_id_seq START 1 INCREMENT 1 NO MINVALUE NO MAXVALUE CACHE 1;
+            CREATE TABLE public.todos (id bigint NOT NULL, status public.status);
+            ALTER TABLE ONLY public.todos ALTER COLUMN id SET DEFAULT nextval('public.todos_id_seq'::regclass);
+            ALTER TABLE ONLY public.todos ADD CONSTRAINT todos_pkey PRIMARY KEY (id);
+            CREATE INDEX todos_status_idx ON public.todos USING btree (status);
+            ALTER TABLE public.todos OWNER TO postgres;
+            ALTER TABLE public.todos_id_seq OWNER TO postgres;
+            CREATE VIEW public.done_todos AS SELECT id FROM public.todos WHERE status = 'done';
+            CREATE SCHEMA IF NOT EXISTS app;
+            CREATE TABLE app.logs (id int);
+            "#,
+        )
+        .load()
+        .await?;
+
+        let rollback = before.rollback(&applied)?;
+        assert_eq!(
+            rollback.sqls(),
+            vec![
+                "DROP VIEW public.done_todos",
+                "DROP TABLE app.logs",
+                "DROP TABLE public.todos",
+                "DROP SEQUENCE public.todos_id_seq",
+                "DROP TYPE public.status",
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn database_schema_fingerprint_should_only_change_with_schema() -> Result<()> {
+        let s1 = todos_schema().await?;
+        let s2 = SqlLoader::new("CREATE TABLE  public.todos ( title TEXT )")
+            .load()
+            .await?;
+        let s3 = SqlLoader::new("CREATE TABLE public.todos (title text, completed boolean)")
+            .load()
+            .await?;
+        assert_eq!(s1.fingerprint().len(), 64);
+        assert_eq!(s1.fingerprint(), s2.fingerprint());
+        assert_ne!(s1.fingerprint(), s3.fingerprint());
+        Ok(())
+    }
 }
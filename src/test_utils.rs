@@ -0,0 +1,9 @@
+use crate::{DatabaseSchema, SchemaLoader, SqlLoader};
+use anyhow::Result;
+
+/// the schema most tests plan from: a todo table with a title only
+pub(crate) async fn todos_schema() -> Result<DatabaseSchema> {
+    SqlLoader::new("CREATE TABLE public.todos (title text)")
+        .load()
+        .await
+}
@@ -0,0 +1,204 @@
+use crate::{ApplyReport, MigrationPlan, StepFailure, StepReport, StepStatus};
+use anyhow::{anyhow, Context, Result};
+use sqlx::postgres::PgDatabaseError;
+use std::{fmt, ops::Range, path::Path, time::Duration};
+use tokio::fs;
+
+impl ApplyReport {
+    pub fn new(plan: &MigrationPlan, target: impl Into<String>) -> Self {
+        let steps = plan
+            .steps
+            .iter()
+            .map(|step| StepReport {
+                sql: step.sql.clone(),
+                id: step.id.clone(),
+                type_name: step.type_name.clone(),
+                status: StepStatus::Pending,
+                duration_ms: None,
+                attempts: 0,
+            })
+            .collect();
+        Self {
+            plan_id: plan.id(),
+            env: None,
+            target: target.into(),
+            started_at: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            duration_ms: 0,
+            succeeded: false,
+            steps,
+            failure: None,
+        }
+    }
+
+    /// record the run of the step at the index
+    pub fn record_step(&mut self, index: usize, duration: Duration, succeeded: bool) {
+        if let Some(step) = self.steps.get_mut(index) {
+            step.status = if succeeded {
+                StepStatus::Applied
+            } else {
+                StepStatus::Failed
+            };
+            step.duration_ms = Some(duration.as_millis() as u64);
+            step.attempts += 1;
+        }
+    }
+
+    /// the transaction of the steps was rolled back
+    pub fn rolled_back(&mut self, range: Range<usize>) {
+        for step in self.steps[range].iter_mut() {
+            if step.status == StepStatus::Applied {
+                step.status = StepStatus::RolledBack;
+            }
+        }
+    }
+
+    /// record the failure, and return the error telling what failed and what was committed
+    pub fn fail(&mut self, failure: StepFailure) -> anyhow::Error {
+        let err = anyhow!(
+            "{}\n{} statement(s) of the plan were committed before the failure.",
+            failure,
+            self.applied()
+        );
+        self.failure = Some(failure);
+        err
+    }
+
+    /// record the duration of the plan, and whether it was applied and recorded. A plan may
+    /// fail without a step failure, e.g. when the migration can't be recorded
+    pub fn finish(&mut self, duration: Duration, succeeded: bool) {
+        self.duration_ms = duration.as_millis() as u64;
+        self.succeeded = succeeded && self.failure.is_none();
+    }
+
+    /// number of the steps committed to the database
+    pub fn applied(&self) -> usize {
+        self.steps
+            .iter()
+            .filter(|step| step.status == StepStatus::Applied)
+            .count()
+    }
+
+    pub async fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let content = serde_json::to_string_pretty(self)?;
+        fs::write(path, content)
+            .await
+            .with_context(|| format!("Failed to write apply report: {}", path.display()))?;
+        Ok(())
+    }
+}
+
+impl StepFailure {
+    /// `step` is the index of the failed step in the plan, if the failure is from one
+    pub fn new(
+        step: Option<usize>,
+        sql: impl Into<String>,
+        e: &sqlx::Error,
+        rolled_back: bool,
+        attempts: u32,
+    ) -> Self {
+        let db_error = e.as_database_error();
+        let pg_error = db_error.and_then(|e| e.try_downcast_ref::<PgDatabaseError>());
+        Self {
+            step: step.map(|i| i + 1),
+            sql: sql.into(),
+            sql_state: db_error
+                .and_then(|e| e.code())
+                .map(|code| code.into_owned()),
+            message: db_error
+                .map(|e| e.message().to_owned())
+                .unwrap_or_else(|| e.to_string()),
+            detail: pg_error.and_then(|e| e.detail()).map(|s| s.to_owned()),
+            hint: pg_error.and_then(|e| e.hint()).map(|s| s.to_owned()),
+            rolled_back,
+            attempts,
+        }
+    }
+}
+
+impl fmt::Display for StepFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.step {
+            Some(step) => writeln!(f, "Statement {} failed: {}", step, self.sql)?,
+            None => writeln!(f, "{} failed", self.sql)?,
+        }
+        match &self.sql_state {
+            Some(code) => writeln!(f, "ERROR [{}]: {}", code, self.message)?,
+            None => writeln!(f, "ERROR: {}", self.message)?,
+        }
+        if let Some(detail) = &self.detail {
+            writeln!(f, "DETAIL: {}", detail)?;
+        }
+        if let Some(hint) = &self.hint {
+            writeln!(f, "HINT: {}", hint)?;
+        }
+        match self.sql_state.as_deref() {
+            // lock_not_available, raised when `lock_timeout` is exceeded
+            Some("55P03") => writeln!(
+                f,
+                "Timed out waiting for a lock after {} attempt(s). Increase `lock_timeout` or `retries`, or apply when the table is less busy.",
+                self.attempts
+            )?,
+            // query_canceled, raised when `statement_timeout` is exceeded
+            Some("57014") => writeln!(
+                f,
+                "Increase `statement_timeout` if the statement is expected to take long."
+            )?,
+            _ => {}
+        }
+        if self.rolled_back {
+            write!(
+                f,
+                "The transaction was rolled back, so none of its statements were applied."
+            )
+        } else {
+            write!(
+                f,
+                "The statement can't run in a transaction. Check the state of the object before applying again."
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::todos_schema;
+    use crate::{SchemaLoader, SqlLoader};
+
+    #[tokio::test]
+    async fn apply_report_should_track_step_status() -> Result<()> {
+        let remote = todos_schema().await?;
+        let local =
+            SqlLoader::new("CREATE TABLE public.todos (title text, completed boolean, due date)")
+                .load()
+                .await?;
+        let plan = local.plan(&remote)?;
+        assert_eq!(plan.steps.len(), 2);
+
+        let mut report = ApplyReport::new(&plan, "postgres://localhost:5432/test");
+        report.record_step(0, Duration::from_millis(5), true);
+        report.record_step(1, Duration::from_millis(7), false);
+        report.rolled_back(0..2);
+        assert_eq!(report.steps[0].status, StepStatus::RolledBack);
+        assert_eq!(report.steps[1].status, StepStatus::Failed);
+
+        let failure = StepFailure::new(
+            Some(1),
+            plan.steps[1].sql.clone(),
+            &sqlx::Error::PoolTimedOut,
+            true,
+            1,
+        );
+        let err = report.fail(failure).to_string();
+        assert!(err.starts_with("Statement 2 failed: ALTER TABLE"));
+        assert!(err.contains("The transaction was rolled back"));
+        assert!(err.contains("0 statement(s) of the plan were committed"));
+
+        report.finish(Duration::from_millis(20), true);
+        assert!(!report.succeeded);
+        let json = serde_json::to_string(&report)?;
+        assert!(json.contains(r#""status":"rolled_back""#));
+        Ok(())
+    }
+}
@@ -0,0 +1,192 @@
+use crate::{
+    normalizer::{parse_stmt, qualified_name, quote_ident},
+    parser::SchemaId,
+    MigrationPlan, MigrationStep, RenovateBackfillConfig,
+};
+use anyhow::{bail, Context, Result};
+use pg_query::{
+    protobuf::{AlterTableType, ColumnDef, ConstrType},
+    Node, NodeEnum,
+};
+
+impl MigrationPlan {
+    /// Split adding a column which has a backfill expression into: adding it as nullable
+    /// without a default, updating the existing rows in batches, then setting the default and
+    /// `NOT NULL`. The column ends up the same as the local definition, without failing on the
+    /// rows already in the table.
+    pub fn backfill(self, config: &RenovateBackfillConfig) -> Result<Self> {
+        if config.columns.is_empty() {
+            return Ok(self);
+        }
+        let mut steps = Vec::with_capacity(self.steps.len());
+        for step in self.steps {
+            match backfill_steps(&step, config)? {
+                Some(backfill) => steps.extend(backfill),
+                None => steps.push(step),
+            }
+        }
+        Self::new(steps)
+    }
+}
+
+/// the steps replacing the `ADD COLUMN` step, if its column has a backfill expression
+fn backfill_steps(
+    step: &MigrationStep,
+    config: &RenovateBackfillConfig,
+) -> Result<Option<Vec<MigrationStep>>> {
+    let mut stmt = match parse_stmt(&step.sql)? {
+        NodeEnum::AlterTableStmt(stmt) if stmt.cmds.len() == 1 => stmt,
+        _ => return Ok(None),
+    };
+    let relation = stmt.relation.clone();
+    let id = SchemaId::from(relation.as_ref());
+    let col = match stmt.cmds[0].node.as_mut() {
+        Some(NodeEnum::AlterTableCmd(cmd)) if cmd.subtype() == AlterTableType::AtAddColumn => {
+            match cmd.def.as_mut().and_then(|n| n.node.as_mut()) {
+                Some(NodeEnum::ColumnDef(col)) => col,
+                _ => return Ok(None),
+            }
+        }
+        _ => return Ok(None),
+    };
+    let name = format!("{}.{}", id, col.colname);
+    let expr = match config.columns.get(&name) {
+        Some(expr) => expr,
+        None => return Ok(None),
+    };
+
+    let (not_null, default) = take_fill_constraints(col, &name)?;
+    let table = qualified_name(relation.as_ref());
+    let column = quote_ident(&col.colname);
+
+    let sql = format!(
+        "UPDATE {} SET {} = ({}) WHERE {} IS NULL",
+        table, column, expr, column
+    );
+    pg_query::parse(&sql)
+        .with_context(|| format!("Invalid backfill expression for {}: {}", name, expr))?;
+    // the batches page through the table by ctid from `$1`, and return the number of updated
+    // rows and the ctid to continue from. Rows the expression is NULL for are left as they
+    // are, and they are not picked again, since the next batch starts after them
+    let batch_sql = format!(
+        "WITH b AS (SELECT ctid AS row_id, ({expr}) AS value FROM {table} WHERE {column} IS NULL AND ctid > $1::tid ORDER BY ctid LIMIT {size}), u AS (UPDATE {table} t SET {column} = b.value FROM b WHERE t.ctid = b.row_id AND b.value IS NOT NULL RETURNING 1) SELECT (SELECT count(*) FROM u), (SELECT row_id::text FROM b ORDER BY row_id DESC LIMIT 1)",
+        table = table,
+        column = column,
+        expr = expr,
+        size = config.batch_size()
+    );
+
+    let new_step =
+        |sql: String| MigrationStep::new(sql, &step.id, &step.type_name, step.action, &step.diff);
+    let mut steps = vec![
+        new_step(NodeEnum::AlterTableStmt(stmt).deparse()?),
+        MigrationStep {
+            batch_sql: Some(batch_sql),
+            ..new_step(sql)
+        },
+    ];
+    if let Some(default) = default {
+        steps.push(new_step(set_default_sql(&table, &column, default)?));
+    }
+    if not_null {
+        steps.push(new_step(format!(
+            "ALTER TABLE ONLY {} ALTER COLUMN {} SET NOT NULL",
+            table, column
+        )));
+    }
+    Ok(Some(steps))
+}
+
+/// remove the `NOT NULL` and `DEFAULT` constraints from the column, and return them
+fn take_fill_constraints(col: &mut ColumnDef, name: &str) -> Result<(bool, Option<Node>)> {
+    let mut not_null = false;
+    let mut default = None;
+    let mut constraints = Vec::with_capacity(col.constraints.len());
+    for node in col.constraints.drain(..) {
+        match &node.node {
+            Some(NodeEnum::Constraint(c)) => match c.contype() {
+                ConstrType::ConstrNotnull => not_null = true,
+                ConstrType::ConstrDefault => default = c.raw_expr.as_deref().cloned(),
+                ConstrType::ConstrIdentity | ConstrType::ConstrGenerated => {
+                    bail!("Column {} is generated, so it can't be backfilled", name)
+                }
+                _ => constraints.push(node),
+            },
+            _ => constraints.push(node),
+        }
+    }
+    col.constraints = constraints;
+    Ok((not_null, default))
+}
+
+/// `ALTER COLUMN ... SET DEFAULT` with the default expression of the column definition
+fn set_default_sql(table: &str, column: &str, default: Node) -> Result<String> {
+    let sql = format!(
+        "ALTER TABLE ONLY {} ALTER COLUMN {} SET DEFAULT NULL",
+        table, column
+    );
+    let mut stmt = match parse_stmt(&sql)? {
+        NodeEnum::AlterTableStmt(stmt) => stmt,
+        _ => unreachable!("should be an alter table statement"),
+    };
+    if let Some(NodeEnum::AlterTableCmd(cmd)) = stmt.cmds[0].node.as_mut() {
+        cmd.def = Some(Box::new(default));
+    }
+    Ok(NodeEnum::AlterTableStmt(stmt).deparse()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::todos_schema;
+    use crate::{SchemaLoader, SqlLoader};
+    use std::collections::BTreeMap;
+
+    #[tokio::test]
+    async fn backfill_should_split_adding_not_null_column() -> Result<()> {
+        let remote = todos_schema().await?;
+        let local = SqlLoader::new(
+            "CREATE TABLE public.todos (title text, status text DEFAULT 'todo'::text NOT NULL, due date)",
+        )
+        .load()
+        .await?;
+        let config = RenovateBackfillConfig {
+            batch_size: Some(500),
+            columns: BTreeMap::from([("public.todos.status".to_owned(), "'done'".to_owned())]),
+        };
+        let plan = local.plan(&remote)?.backfill(&config)?;
+        let sqls = plan.sqls();
+        assert_eq!(sqls.len(), 5);
+        assert!(!sqls[0].contains("NOT NULL") && !sqls[0].contains("DEFAULT"));
+        assert_eq!(
+            sqls[1],
+            r#"UPDATE "public"."todos" SET "status" = ('done') WHERE "status" IS NULL"#
+        );
+        assert!(!plan.steps[1].transactional);
+        let batch_sql = plan.steps[1].batch_sql.as_deref().unwrap();
+        assert!(batch_sql.contains("LIMIT 500"));
+        assert!(batch_sql.contains("ctid > $1::tid ORDER BY ctid"));
+        pg_query::parse(batch_sql)?;
+        assert!(sqls[2].contains("SET DEFAULT 'todo'"));
+        assert_eq!(
+            sqls[3],
+            r#"ALTER TABLE ONLY "public"."todos" ALTER COLUMN "status" SET NOT NULL"#
+        );
+        assert!(plan.steps[4].batch_sql.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn backfill_should_reject_invalid_expression() -> Result<()> {
+        let remote = todos_schema().await?;
+        let local = SqlLoader::new("CREATE TABLE public.todos (title text, status text NOT NULL)")
+            .load()
+            .await?;
+        let config = RenovateBackfillConfig {
+            batch_size: None,
+            columns: BTreeMap::from([("public.todos.status".to_owned(), "'done".to_owned())]),
+        };
+        assert!(local.plan(&remote)?.backfill(&config).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,67 @@
+use crate::EnvironmentState;
+use anyhow::{Context, Result};
+use std::{collections::BTreeMap, path::Path};
+use tokio::fs;
+
+/// the file in the local repo to save the environment states. Files under `_` directories are
+/// not loaded as schema
+const ENVIRONMENTS_FILE: &str = "_renovate/environments.yml";
+
+impl EnvironmentState {
+    pub fn new(commit_id: Option<String>, plan_id: String) -> Self {
+        Self {
+            commit_id,
+            plan_id,
+            applied_at: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        }
+    }
+
+    /// load the states of all the environments from the local repo
+    pub async fn load_all(repo: impl AsRef<Path>) -> Result<BTreeMap<String, Self>> {
+        let path = repo.as_ref().join(ENVIRONMENTS_FILE);
+        if !path.exists() {
+            return Ok(BTreeMap::new());
+        }
+        let content = fs::read_to_string(&path).await?;
+        serde_yaml::from_str(&content)
+            .with_context(|| format!("Failed to parse environment states: {}", path.display()))
+    }
+
+    /// save the state of the environment to the local repo
+    pub async fn save(&self, repo: impl AsRef<Path>, env: &str) -> Result<()> {
+        let repo = repo.as_ref();
+        let mut states = Self::load_all(repo).await?;
+        states.insert(env.to_owned(), self.clone());
+
+        let path = repo.join(ENVIRONMENTS_FILE);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).await?;
+        }
+        fs::write(&path, serde_yaml::to_string(&states)?)
+            .await
+            .with_context(|| format!("Failed to write environment states: {}", path.display()))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn environment_state_should_be_saved_per_environment() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        assert!(EnvironmentState::load_all(dir.path()).await?.is_empty());
+
+        let staging = EnvironmentState::new(Some("abc".into()), "plan1".into());
+        staging.save(dir.path(), "staging").await?;
+        let prod = EnvironmentState::new(None, "plan0".into());
+        prod.save(dir.path(), "prod").await?;
+
+        let states = EnvironmentState::load_all(dir.path()).await?;
+        assert_eq!(states.len(), 2);
+        assert_eq!(states["staging"], staging);
+        assert_eq!(states["prod"], prod);
+        Ok(())
+    }
+}
@@ -0,0 +1,266 @@
+use crate::{
+    HookKind, MigrationAction, MigrationHook, MigrationPhase, MigrationPlan, MigrationStep,
+    PlanObject,
+};
+use anyhow::{bail, Context, Result};
+
+impl MigrationHook {
+    /// the table the applied hooks are recorded in, so that each hook runs only once
+    pub(crate) const CREATE_TABLE: [&'static str; 2] = [
+        "CREATE SCHEMA IF NOT EXISTS _renovate",
+        "CREATE TABLE IF NOT EXISTS _renovate.hooks (name text PRIMARY KEY, applied_at timestamptz NOT NULL DEFAULT now(), applied_by text NOT NULL DEFAULT current_user)",
+    ];
+
+    /// parse the script. The objects it relates to are listed in its leading comments, e.g.
+    /// `-- objects: public.users, public.todos`
+    pub fn new(name: impl Into<String>, kind: HookKind, sql: impl Into<String>) -> Result<Self> {
+        let name = name.into();
+        let sql = sql.into();
+        let parsed =
+            pg_query::parse(&sql).with_context(|| format!("Failed to parse hook {}", name))?;
+        if parsed.protobuf.stmts.is_empty() {
+            bail!("Hook {} has no statement", name);
+        }
+
+        let objects = sql
+            .lines()
+            .map(str::trim)
+            .take_while(|line| line.is_empty() || line.starts_with("--"))
+            .filter_map(|line| line.trim_start_matches('-').trim().strip_prefix("objects:"))
+            .flat_map(|ids| ids.split(','))
+            .map(|id| id.trim().to_owned())
+            .filter(|id| !id.is_empty())
+            .collect();
+        Ok(Self {
+            name,
+            kind,
+            objects,
+            sql: sql.trim().to_owned(),
+        })
+    }
+}
+
+impl MigrationPlan {
+    /// Insert the hooks as steps of the plan. A pre hook runs before the steps of the first
+    /// object it is tagged with. A post hook runs after the pre-deploy steps of the last one,
+    /// and before the post-deploy ones like dropping columns, so that a data migration can
+    /// copy a column before it's dropped. Hooks without objects run at the start or the end of
+    /// the plan, and the ones whose objects are not changed are left out.
+    pub fn with_hooks(self, hooks: &[MigrationHook]) -> Result<Self> {
+        if self.steps.is_empty() || hooks.is_empty() {
+            return Ok(self);
+        }
+
+        let mut hooks: Vec<_> = hooks.iter().collect();
+        hooks.sort_by(|a, b| a.name.cmp(&b.name));
+        let objects = self.objects();
+        // the index of the object each hook runs with
+        let object_of = |hook: &MigrationHook| {
+            let related = |o: &PlanObject| hook.objects.iter().any(|id| id == o.id);
+            match hook.kind {
+                HookKind::Pre => objects.iter().position(related),
+                HookKind::Post => objects.iter().rposition(related),
+            }
+        };
+        let placed: Vec<_> = hooks.iter().map(|hook| (object_of(hook), *hook)).collect();
+        let hooks_of = |index: Option<usize>, kind: HookKind| {
+            placed
+                .iter()
+                .filter(move |(i, hook)| {
+                    hook.kind == kind && *i == index && (index.is_some() || hook.objects.is_empty())
+                })
+                .map(|(_, hook)| *hook)
+        };
+
+        let first = self.steps[0].phase;
+        let last = self.steps[self.steps.len() - 1].phase;
+        let mut steps = Vec::with_capacity(self.steps.len() + hooks.len());
+        steps.extend(hooks_of(None, HookKind::Pre).map(|h| hook_step(h, first)));
+        for (i, object) in objects.iter().enumerate() {
+            let phase = object.steps[0].phase;
+            steps.extend(hooks_of(Some(i), HookKind::Pre).map(|h| hook_step(h, phase)));
+
+            let mut post = hooks_of(Some(i), HookKind::Post).peekable();
+            if post.peek().is_none() {
+                steps.extend(object.steps.iter().cloned());
+                continue;
+            }
+            let (expand, contract): (Vec<_>, Vec<_>) = object
+                .steps
+                .iter()
+                .cloned()
+                .partition(|s| s.phase == MigrationPhase::Pre);
+            let phase = if expand.is_empty() {
+                MigrationPhase::Post
+            } else {
+                MigrationPhase::Pre
+            };
+            steps.extend(expand);
+            steps.extend(post.map(|h| hook_step(h, phase)));
+            steps.extend(contract);
+        }
+        steps.extend(hooks_of(None, HookKind::Post).map(|h| hook_step(h, last)));
+        Self::new(steps)
+    }
+
+    /// the hooks which are pending but not in the plan, since none of their objects is changed
+    /// (or the plan is empty). They are not recorded, and run with a later plan changing them
+    pub fn left_out_hooks<'a>(&self, hooks: &'a [MigrationHook]) -> Vec<&'a MigrationHook> {
+        hooks
+            .iter()
+            .filter(|hook| {
+                !self
+                    .steps
+                    .iter()
+                    .any(|s| s.hook.as_deref() == Some(hook.name.as_str()))
+            })
+            .collect()
+    }
+
+    /// the plan as a script run without renovate, e.g. a migration file: each hook is followed
+    /// by recording it in `_renovate.hooks`, in the same transaction, so that `apply` never
+    /// runs it again
+    pub fn with_hook_records(&self) -> Self {
+        let mut steps = Vec::with_capacity(self.steps.len());
+        let mut created = false;
+        for step in &self.steps {
+            let name = match &step.hook {
+                Some(name) => name,
+                None => {
+                    steps.push(step.clone());
+                    continue;
+                }
+            };
+            if !created {
+                steps.extend(
+                    MigrationHook::CREATE_TABLE
+                        .iter()
+                        .map(|sql| record_step(sql, name, step.phase)),
+                );
+                created = true;
+            }
+            steps.push(step.clone());
+            let sql = format!(
+                "INSERT INTO _renovate.hooks (name) VALUES ('{}')",
+                name.replace('\'', "''")
+            );
+            steps.push(record_step(&sql, name, step.phase));
+        }
+        Self { steps }
+    }
+}
+
+/// the step running the hook in the phase
+fn hook_step(hook: &MigrationHook, phase: MigrationPhase) -> MigrationStep {
+    MigrationStep {
+        phase,
+        hook: Some(hook.name.clone()),
+        ..MigrationStep::new(&hook.sql, &hook.name, "hook", MigrationAction::Alter, "")
+    }
+}
+
+/// the step recording the hook, in the phase of the hook
+fn record_step(sql: &str, name: &str, phase: MigrationPhase) -> MigrationStep {
+    MigrationStep {
+        phase,
+        ..MigrationStep::new(sql, name, "hook record", MigrationAction::Alter, "")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{SchemaLoader, SqlLoader};
+
+    #[test]
+    fn hook_should_parse_objects_from_header() -> Result<()> {
+        let hook = MigrationHook::new(
+            "post/01_split_name.sql",
+            HookKind::Post,
+            "-- split the name\n-- objects: public.users, public.profiles\nUPDATE public.users SET first_name = split_part(name, ' ', 1);\n-- objects: public.ignored\n",
+        )?;
+        assert_eq!(hook.objects, vec!["public.users", "public.profiles"]);
+        assert!(MigrationHook::new("pre/empty.sql", HookKind::Pre, "-- nothing").is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn hooks_should_run_around_their_objects() -> Result<()> {
+        let remote = SqlLoader::new(
+            "CREATE TABLE public.users (name text); CREATE TABLE public.todos (title text)",
+        )
+        .load()
+        .await?;
+        let local = SqlLoader::new(
+            "CREATE TABLE public.users (first_name varchar(100)); CREATE TABLE public.todos (title text, due date)",
+        )
+        .load()
+        .await?;
+        let hooks = vec![
+            MigrationHook::new(
+                "post/01_split_name.sql",
+                HookKind::Post,
+                "-- objects: public.users\nUPDATE public.users SET first_name = name",
+            )?,
+            MigrationHook::new(
+                "pre/01_todos.sql",
+                HookKind::Pre,
+                "-- objects: public.todos\nSELECT 1",
+            )?,
+            MigrationHook::new(
+                "pre/02_other.sql",
+                HookKind::Pre,
+                "-- objects: public.other\nSELECT 1",
+            )?,
+        ];
+        let plan = local.plan(&remote)?.with_hooks(&hooks)?;
+        let names: Vec<_> = plan
+            .steps
+            .iter()
+            .map(|s| s.hook.as_deref().unwrap_or(&s.sql))
+            .collect();
+        let position = |s: &str| names.iter().position(|n| n.contains(s)).unwrap();
+        assert!(position("pre/01_todos.sql") < position("ADD COLUMN due"));
+        assert!(position("ADD COLUMN first_name") < position("post/01_split_name.sql"));
+        assert!(position("post/01_split_name.sql") < position("DROP COLUMN name"));
+        assert!(!names.contains(&"pre/02_other.sql"));
+
+        let hook = &plan.steps[position("post/01_split_name.sql")];
+        assert_eq!(hook.phase, MigrationPhase::Pre);
+        assert!(hook.transactional);
+
+        let left_out: Vec<_> = plan
+            .left_out_hooks(&hooks)
+            .iter()
+            .map(|h| &h.name)
+            .collect();
+        assert_eq!(left_out, vec!["pre/02_other.sql"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn hook_records_should_follow_their_hooks() -> Result<()> {
+        let remote = SqlLoader::new("CREATE TABLE public.users (name text)")
+            .load()
+            .await?;
+        let local = SqlLoader::new("CREATE TABLE public.users (name text, nick text)")
+            .load()
+            .await?;
+        let hooks = vec![MigrationHook::new(
+            "post/it's.sql",
+            HookKind::Post,
+            "-- objects: public.users\nUPDATE public.users SET nick = name",
+        )?];
+        let plan = local.plan(&remote)?.with_hooks(&hooks)?.with_hook_records();
+        let sqls: Vec<_> = plan.steps.iter().map(|s| s.sql.as_str()).collect();
+        assert_eq!(sqls[0], "ALTER TABLE public.users ADD COLUMN nick text");
+        assert!(sqls[1].starts_with("CREATE SCHEMA IF NOT EXISTS _renovate"));
+        assert!(sqls[2].starts_with("CREATE TABLE IF NOT EXISTS _renovate.hooks"));
+        assert_eq!(sqls[3], "UPDATE public.users SET nick = name");
+        assert_eq!(
+            sqls[4],
+            "INSERT INTO _renovate.hooks (name) VALUES ('post/it''s.sql')"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,486 @@
+use crate::{
+    analyzer::is_transactional, config::RenovateFormatConfig, utils::create_unified_diff, LockMode,
+    MigrationAction, MigrationPhase, MigrationPlan, MigrationPlanner, MigrationStep, NodeDiff,
+    NodeItem, PlanObject, RiskLevel, StatementLock,
+};
+use anyhow::Result;
+use sha2::{Digest, Sha256};
+use std::fmt::{self, Write};
+
+impl MigrationPlan {
+    /// build the plan from the steps, and analyze the lock, risk and transaction of each step
+    pub fn new(mut steps: Vec<MigrationStep>) -> Result<Self> {
+        let sqls: Vec<_> = steps.iter().map(|s| s.sql.clone()).collect();
+        let locks = StatementLock::analyze_all(&sqls)?;
+        for (step, lock) in steps.iter_mut().zip(locks) {
+            step.transactional = step.batch_sql.is_none() && is_transactional(&step.sql)?;
+            step.risk = RiskLevel::assess(&step.sql, &lock)?;
+            step.lock = lock;
+            // a hook runs in the phase of the steps it's placed with
+            if step.hook.is_none() {
+                step.phase = if step.action == MigrationAction::Drop {
+                    MigrationPhase::Post
+                } else {
+                    MigrationPhase::of(&step.sql)?
+                };
+            }
+        }
+        keep_objects_in_one_phase(&mut steps);
+        Ok(Self { steps })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+
+    /// flag the rollback steps which recreate the objects that lost data in the forward plan,
+    /// since rolling back can't bring the data back
+    pub fn flag_lossy(&mut self, forward: &MigrationPlan) {
+        let lost: Vec<_> = forward
+            .steps
+            .iter()
+            .filter(|s| s.risk == RiskLevel::High)
+            .map(|s| (&s.id, &s.type_name))
+            .collect();
+        for step in &mut self.steps {
+            step.lossy =
+                step.action != MigrationAction::Drop && lost.contains(&(&step.id, &step.type_name));
+        }
+    }
+
+    /// a stable id for the plan, derived from its statements
+    pub fn id(&self) -> String {
+        let mut hasher = Sha256::new();
+        for step in &self.steps {
+            hasher.update(step.sql.as_bytes());
+            hasher.update(b";\n");
+        }
+        format!("{:x}", hasher.finalize())[..12].to_owned()
+    }
+
+    /// all the SQL statements of the plan
+    pub fn sqls(&self) -> Vec<String> {
+        self.steps.iter().map(|s| s.sql.clone()).collect()
+    }
+
+    /// the highest risk level of all the steps
+    pub fn risk(&self) -> RiskLevel {
+        self.steps
+            .iter()
+            .map(|s| s.risk)
+            .max()
+            .unwrap_or(RiskLevel::Low)
+    }
+
+    /// group consecutive steps by the schema object they belong to
+    pub fn objects(&self) -> Vec<PlanObject> {
+        split_when(&self.steps, |a, b| {
+            a.id != b.id || a.type_name != b.type_name
+        })
+        .into_iter()
+        .map(|steps| PlanObject {
+            id: &steps[0].id,
+            type_name: &steps[0].type_name,
+            steps,
+        })
+        .collect()
+    }
+
+    /// render the plan as a markdown report, e.g. for a pull request comment
+    pub fn to_markdown(&self) -> Result<String> {
+        let mut output = String::new();
+        writeln!(output, "## Migration plan\n")?;
+        if self.is_empty() {
+            writeln!(output, "No changes detected.")?;
+            return Ok(output);
+        }
+
+        let objects = self.objects();
+        let count = |action| objects.iter().filter(|o| o.action() == action).count();
+        writeln!(
+            output,
+            "**{}** added, **{}** changed, **{}** dropped. Overall risk: **{}**.\n",
+            count(MigrationAction::Create),
+            count(MigrationAction::Alter),
+            count(MigrationAction::Drop),
+            self.risk()
+        )?;
+
+        writeln!(output, "| Object | Type | Action | Risk | Lock |")?;
+        writeln!(output, "| --- | --- | --- | --- | --- |")?;
+        for object in &objects {
+            let lock = object
+                .lock_mode()
+                .map(|mode| mode.to_string())
+                .unwrap_or_else(|| "-".to_owned());
+            writeln!(
+                output,
+                "| `{}` | {} | {} | {} | {} |",
+                object.id,
+                object.type_name,
+                object.action(),
+                object.risk(),
+                lock
+            )?;
+        }
+
+        let format = RenovateFormatConfig::default().into();
+        for object in &objects {
+            writeln!(output, "\n<details>")?;
+            writeln!(
+                output,
+                "<summary><code>{}</code> ({} {})</summary>\n",
+                object.id,
+                object.type_name,
+                object.action()
+            )?;
+            let diff = object.diff();
+            if !diff.is_empty() {
+                writeln!(output, "```diff\n{}```\n", diff)?;
+            }
+            writeln!(output, "```sql")?;
+            for step in object.steps {
+                let sql = sqlformat::format(&step.sql, &Default::default(), format);
+                writeln!(output, "{};", sql)?;
+            }
+            writeln!(output, "```\n\n</details>")?;
+        }
+
+        Ok(output)
+    }
+
+    /// render the rollback plan as a markdown section, lossy steps are called out
+    pub fn to_markdown_rollback(&self) -> Result<String> {
+        let mut output = String::new();
+        writeln!(output, "\n### Rollback\n")?;
+        if self.is_empty() {
+            writeln!(output, "Nothing to roll back.")?;
+            return Ok(output);
+        }
+
+        let lossy = self.steps.iter().filter(|s| s.lossy).count();
+        if lossy > 0 {
+            writeln!(
+                output,
+                "> **Warning**: {} rollback statement(s) can't restore the data lost by the plan.\n",
+                lossy
+            )?;
+        }
+
+        let format = RenovateFormatConfig::default().into();
+        writeln!(
+            output,
+            "<details>\n<summary>{} statement(s)</summary>\n",
+            self.len()
+        )?;
+        writeln!(output, "```sql")?;
+        for step in &self.steps {
+            if step.lossy {
+                writeln!(output, "-- WARNING: can't restore the lost data")?;
+            }
+            let sql = sqlformat::format(&step.sql, &Default::default(), format);
+            writeln!(output, "{};", sql)?;
+        }
+        writeln!(output, "```\n\n</details>")?;
+        Ok(output)
+    }
+
+    /// the steps of the phase, e.g. to deploy them separately
+    pub fn phase(&self, phase: MigrationPhase) -> Self {
+        let steps = self
+            .steps
+            .iter()
+            .filter(|s| s.phase == phase)
+            .cloned()
+            .collect();
+        Self { steps }
+    }
+
+    /// whether the plan has any step of the phase
+    pub fn has_phase(&self, phase: MigrationPhase) -> bool {
+        self.steps.iter().any(|s| s.phase == phase)
+    }
+
+    /// split the steps into batches. Consecutive transactional steps are in the same batch,
+    /// while each non-transactional step has its own batch.
+    pub fn batches(&self) -> Vec<&[MigrationStep]> {
+        split_when(&self.steps, |a, b| !a.transactional || !b.transactional)
+    }
+}
+
+impl MigrationStep {
+    pub fn new(
+        sql: impl Into<String>,
+        id: impl Into<String>,
+        type_name: impl Into<String>,
+        action: MigrationAction,
+        diff: impl Into<String>,
+    ) -> Self {
+        Self {
+            sql: sql.into(),
+            id: id.into(),
+            type_name: type_name.into(),
+            action,
+            transactional: true,
+            risk: RiskLevel::Low,
+            lock: StatementLock::default(),
+            diff: diff.into(),
+            lossy: false,
+            batch_sql: None,
+            phase: MigrationPhase::Pre,
+            hook: None,
+        }
+    }
+}
+
+impl<'a> PlanObject<'a> {
+    /// the object is added or dropped if all of its steps do so, otherwise it is changed
+    pub fn action(&self) -> MigrationAction {
+        let first = self.steps[0].action;
+        if first != MigrationAction::Alter && self.steps.iter().all(|s| s.action == first) {
+            first
+        } else {
+            MigrationAction::Alter
+        }
+    }
+
+    pub fn risk(&self) -> RiskLevel {
+        self.steps
+            .iter()
+            .map(|s| s.risk)
+            .max()
+            .unwrap_or(RiskLevel::Low)
+    }
+
+    /// the strongest lock acquired by the steps of the object
+    pub fn lock_mode(&self) -> Option<LockMode> {
+        self.steps.iter().filter_map(|s| s.lock.mode).max()
+    }
+
+    pub fn diff(&self) -> &'a str {
+        &self.steps[0].diff
+    }
+}
+
+impl fmt::Display for MigrationAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            MigrationAction::Create => "create",
+            MigrationAction::Alter => "alter",
+            MigrationAction::Drop => "drop",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl<T> NodeDiff<T>
+where
+    T: NodeItem,
+    NodeDiff<T>: MigrationPlanner<Migration = String>,
+{
+    /// generate migration steps in the same way as `MigrationPlanner::plan`, but keep the
+    /// object and action for each statement
+    pub fn steps(&self) -> Result<Vec<MigrationStep>> {
+        let item = match (&self.new, &self.old) {
+            (Some(item), _) | (None, Some(item)) => item,
+            (None, None) => return Ok(vec![]),
+        };
+        let (id, type_name) = (item.id(), item.type_name());
+        let diff = create_unified_diff(self.old.as_ref(), self.new.as_ref());
+        let to_steps = |sqls: Vec<String>, action| {
+            sqls.into_iter()
+                .map(|sql| MigrationStep::new(sql, &id, type_name, action, &diff))
+                .collect::<Vec<_>>()
+        };
+
+        let items = self.alter()?;
+        if !items.is_empty() {
+            return Ok(to_steps(items, MigrationAction::Alter));
+        }
+
+        let mut steps = to_steps(self.drop()?, MigrationAction::Drop);
+        steps.extend(to_steps(self.create()?, MigrationAction::Create));
+        Ok(steps)
+    }
+}
+
+/// an object dropped and created again, e.g. a changed index or view, can't have the drop
+/// after the creation, so all the steps of such an object are applied before deploying the app
+fn keep_objects_in_one_phase(steps: &mut [MigrationStep]) {
+    let mut start = 0;
+    for i in 1..=steps.len() {
+        if i < steps.len()
+            && steps[i].id == steps[i - 1].id
+            && steps[i].type_name == steps[i - 1].type_name
+        {
+            continue;
+        }
+        let object = &mut steps[start..i];
+        let recreated = object.iter().any(|s| s.action == MigrationAction::Drop)
+            && object.iter().any(|s| s.action == MigrationAction::Create);
+        if recreated {
+            object
+                .iter_mut()
+                .for_each(|s| s.phase = MigrationPhase::Pre);
+        }
+        start = i;
+    }
+}
+
+/// split the steps into slices between any two adjacent steps matching the predicate
+fn split_when<F>(steps: &[MigrationStep], f: F) -> Vec<&[MigrationStep]>
+where
+    F: Fn(&MigrationStep, &MigrationStep) -> bool,
+{
+    let mut slices = Vec::new();
+    let mut start = 0;
+    for i in 1..=steps.len() {
+        if i == steps.len() || f(&steps[i - 1], &steps[i]) {
+            slices.push(&steps[start..i]);
+            start = i;
+        }
+    }
+    slices
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::todos_schema;
+    use crate::{SchemaLoader, SqlLoader};
+
+    #[tokio::test]
+    async fn migration_plan_should_record_object_and_action() -> Result<()> {
+        let remote = SqlLoader::new(
+            "CREATE TABLE public.todos (title text); CREATE VIEW public.v AS SELECT 1",
+        )
+        .load()
+        .await?;
+        let local = SqlLoader::new(
+            "CREATE TABLE public.todos (title text, completed boolean); CREATE VIEW public.v AS SELECT 2",
+        )
+        .load()
+        .await?;
+        let plan = local.plan(&remote)?;
+        assert_eq!(plan.len(), 3);
+
+        let step = &plan.steps[0];
+        assert_eq!(step.id, "public.todos");
+        assert_eq!(step.type_name, "table");
+        assert_eq!(step.action, MigrationAction::Alter);
+        assert_eq!(step.risk, RiskLevel::Medium);
+        assert!(step.transactional);
+        assert!(step.diff.contains("+    completed boolean"));
+
+        assert_eq!(plan.steps[1].action, MigrationAction::Drop);
+        assert_eq!(plan.steps[2].action, MigrationAction::Create);
+        assert_eq!(plan.batches().len(), 1);
+        assert_eq!(plan.id().len(), 12);
+        assert_eq!(plan.id(), local.plan(&remote)?.id());
+
+        let json = serde_json::to_string(&plan)?;
+        let plan1: MigrationPlan = serde_json::from_str(&json)?;
+        assert_eq!(plan, plan1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn migration_plan_should_split_into_phases() -> Result<()> {
+        let remote = SqlLoader::new(
+            "CREATE TABLE public.todos (title text, legacy text); CREATE TABLE public.old_logs (id int); CREATE VIEW public.v AS SELECT 1",
+        )
+        .load()
+        .await?;
+        let local = SqlLoader::new(
+            "CREATE TABLE public.todos (title text, due date); CREATE VIEW public.v AS SELECT 2",
+        )
+        .load()
+        .await?;
+        let plan = local.plan(&remote)?;
+        let phase_of = |pattern: &str| {
+            plan.steps
+                .iter()
+                .find(|s| s.sql.contains(pattern))
+                .map(|s| s.phase)
+                .unwrap()
+        };
+        assert_eq!(phase_of("ADD COLUMN due"), MigrationPhase::Pre);
+        assert_eq!(phase_of("DROP COLUMN legacy"), MigrationPhase::Post);
+        assert_eq!(phase_of("DROP TABLE"), MigrationPhase::Post);
+        // the view is dropped and created again, so both are before the deploy
+        assert_eq!(phase_of("DROP VIEW"), MigrationPhase::Pre);
+
+        let post = plan.phase(MigrationPhase::Post);
+        assert_eq!(post.len(), 2);
+        assert!(plan.has_phase(MigrationPhase::Pre));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn migration_plan_should_render_markdown() -> Result<()> {
+        let remote = todos_schema().await?;
+        let local = SqlLoader::new(
+            "CREATE TABLE public.todos (title text, completed boolean); CREATE VIEW public.v AS SELECT 1",
+        )
+        .load()
+        .await?;
+        let plan = local.plan(&remote)?;
+        let objects = plan.objects();
+        assert_eq!(objects.len(), 2);
+        assert_eq!(objects[0].action(), MigrationAction::Alter);
+        assert_eq!(objects[1].action(), MigrationAction::Create);
+
+        let md = plan.to_markdown()?;
+        assert!(md.contains("**1** added, **1** changed, **0** dropped."));
+        assert!(md.contains("| `public.todos` | table | alter | medium | ACCESS EXCLUSIVE |"));
+        assert!(md.contains("| `public.v` | view | create | low | - |"));
+        assert!(md.contains("<summary><code>public.v</code> (view create)</summary>"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rollback_should_flag_steps_which_cannot_restore_data() -> Result<()> {
+        let remote = SqlLoader::new(
+            "CREATE TABLE public.todos (title text, completed boolean); CREATE TABLE public.t (id int)",
+        )
+        .load()
+        .await?;
+        let local = SqlLoader::new(
+            "CREATE TABLE public.todos (title text); CREATE TABLE public.t1 (id int)",
+        )
+        .load()
+        .await?;
+        let plan = local.plan(&remote)?;
+        let mut rollback = remote.rollback(&local)?;
+        rollback.flag_lossy(&plan);
+
+        let lossy: Vec<_> = rollback
+            .steps
+            .iter()
+            .filter(|s| s.lossy)
+            .map(|s| s.id.as_str())
+            .collect();
+        assert_eq!(lossy, vec!["public.todos", "public.t"]);
+        assert!(rollback
+            .to_markdown_rollback()?
+            .contains("2 rollback statement(s)"));
+        Ok(())
+    }
+
+    #[test]
+    fn non_transactional_steps_should_be_in_separate_batches() {
+        let step = |sql: &str| MigrationStep::new(sql, "id", "index", MigrationAction::Create, "");
+        let mut steps = vec![step("a"), step("b"), step("c"), step("d")];
+        steps[2].transactional = false;
+        let plan = MigrationPlan { steps };
+        let batches = plan.batches();
+        assert_eq!(batches.len(), 3);
+        assert_eq!(batches[0].len(), 2);
+        assert_eq!(batches[1][0].sql, "c");
+        assert_eq!(batches[2][0].sql, "d");
+    }
+}
@@ -0,0 +1,258 @@
+use crate::{MigrationPlan, MigrationStyle};
+use anyhow::{bail, Result};
+use sqlformat::FormatOptions;
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use tokio::fs;
+
+impl MigrationStyle {
+    /// write the plan as an up migration, and the rollback as a down migration if the tool
+    /// supports it. Returns the written files.
+    pub async fn emit(
+        &self,
+        dir: impl AsRef<Path>,
+        name: &str,
+        plan: &MigrationPlan,
+        rollback: &MigrationPlan,
+        format: FormatOptions,
+    ) -> Result<Vec<PathBuf>> {
+        // refinery runs each migration in a transaction, which statements like
+        // `CREATE INDEX CONCURRENTLY` can't run in. Backfills are written as a single update,
+        // which can
+        let no_tx = plan
+            .steps
+            .iter()
+            .find(|s| !s.transactional && s.batch_sql.is_none());
+        if let (MigrationStyle::Refinery, Some(step)) = (self, no_tx) {
+            bail!(
+                "refinery runs each migration in a transaction, but this statement can't run in one: {}. Use another style, or apply the plan with `renovate schema apply`.",
+                step.sql
+            );
+        }
+
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir).await?;
+
+        let version = match self {
+            MigrationStyle::Refinery => next_refinery_version(dir).await?.to_string(),
+            _ => chrono::Utc::now().format("%Y%m%d%H%M%S").to_string(),
+        };
+
+        let mut written = Vec::new();
+        for (filename, content) in self.files(&version, name, plan, rollback, format) {
+            let path = dir.join(filename);
+            if path.exists() {
+                bail!("migration file {} already exists", path.display());
+            }
+            fs::write(&path, content).await?;
+            written.push(path);
+        }
+        Ok(written)
+    }
+
+    /// generate the file names and contents of the migration
+    pub fn files(
+        &self,
+        version: &str,
+        name: &str,
+        plan: &MigrationPlan,
+        rollback: &MigrationPlan,
+        format: FormatOptions,
+    ) -> Vec<(String, String)> {
+        let up = render(plan, format);
+        let down = render(rollback, format);
+        let no_tx = |plan: &MigrationPlan| plan.steps.iter().any(|s| !s.transactional);
+
+        match self {
+            MigrationStyle::Sqlx => {
+                // sqlx runs each migration in a transaction unless told otherwise
+                let directive = |plan| {
+                    if no_tx(plan) {
+                        "-- no-transaction\n"
+                    } else {
+                        ""
+                    }
+                };
+                vec![
+                    (
+                        format!("{}_{}.up.sql", version, name),
+                        format!("{}{}", directive(plan), up),
+                    ),
+                    (
+                        format!("{}_{}.down.sql", version, name),
+                        format!("{}{}", directive(rollback), down),
+                    ),
+                ]
+            }
+            MigrationStyle::Flyway => vec![
+                (format!("V{}__{}.sql", version, name), up),
+                (format!("U{}__{}.sql", version, name), down),
+            ],
+            MigrationStyle::Refinery => vec![(format!("V{}__{}.sql", version, name), up)],
+            MigrationStyle::Dbmate => {
+                let directive = |plan| {
+                    if no_tx(plan) {
+                        " transaction:false"
+                    } else {
+                        ""
+                    }
+                };
+                let content = format!(
+                    "-- migrate:up{}\n{}\n-- migrate:down{}\n{}",
+                    directive(plan),
+                    up,
+                    directive(rollback),
+                    down
+                );
+                vec![(format!("{}_{}.sql", version, name), content)]
+            }
+        }
+    }
+}
+
+impl FromStr for MigrationStyle {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "sqlx" => Ok(MigrationStyle::Sqlx),
+            "flyway" => Ok(MigrationStyle::Flyway),
+            "refinery" => Ok(MigrationStyle::Refinery),
+            "dbmate" => Ok(MigrationStyle::Dbmate),
+            _ => bail!(
+                "invalid migration style: {}. Expected sqlx, flyway, refinery or dbmate",
+                s
+            ),
+        }
+    }
+}
+
+impl fmt::Display for MigrationStyle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            MigrationStyle::Sqlx => "sqlx",
+            MigrationStyle::Flyway => "flyway",
+            MigrationStyle::Refinery => "refinery",
+            MigrationStyle::Dbmate => "dbmate",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+fn render(plan: &MigrationPlan, format: FormatOptions) -> String {
+    plan.with_hook_records()
+        .steps
+        .iter()
+        .map(|step| {
+            let sql = sqlformat::format(&step.sql, &Default::default(), format);
+            match step.batch_sql {
+                // only `renovate schema apply` runs backfills in batches
+                Some(_) => format!(
+                    "-- updates all rows at once, unlike `renovate schema apply`\n{};\n",
+                    sql
+                ),
+                None => format!("{};\n", sql),
+            }
+        })
+        .collect()
+}
+
+/// refinery versions are integers, so we use the next one after the existing migrations
+async fn next_refinery_version(dir: &Path) -> Result<u64> {
+    let mut version = 0;
+    let mut entries = fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let filename = entry.file_name();
+        let v = filename
+            .to_str()
+            .and_then(|s| s.strip_prefix('V'))
+            .and_then(|s| s.split("__").next())
+            .and_then(|s| s.parse::<u64>().ok());
+        if let Some(v) = v {
+            version = version.max(v);
+        }
+    }
+    Ok(version + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::todos_schema;
+    use crate::{
+        config::RenovateFormatConfig, MigrationAction, MigrationStep, SchemaLoader, SqlLoader,
+    };
+
+    async fn plans() -> Result<(MigrationPlan, MigrationPlan)> {
+        let remote = todos_schema().await?;
+        let local = SqlLoader::new("CREATE TABLE public.todos (title text, completed boolean)")
+            .load()
+            .await?;
+        Ok((local.plan(&remote)?, remote.rollback(&local)?))
+    }
+
+    #[tokio::test]
+    async fn sqlx_style_should_generate_up_and_down_files() -> Result<()> {
+        let (plan, rollback) = plans().await?;
+        let format = RenovateFormatConfig::default().into();
+        let files = MigrationStyle::Sqlx.files("20230101000000", "todo", &plan, &rollback, format);
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].0, "20230101000000_todo.up.sql");
+        assert!(files[0].1.contains("completed boolean;"));
+        assert_eq!(files[1].0, "20230101000000_todo.down.sql");
+        assert!(files[1].1.contains("completed;"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dbmate_style_should_generate_a_single_file() -> Result<()> {
+        let (plan, rollback) = plans().await?;
+        let format = RenovateFormatConfig::default().into();
+        let files =
+            MigrationStyle::Dbmate.files("20230101000000", "todo", &plan, &rollback, format);
+        assert_eq!(files.len(), 1);
+        assert!(files[0].1.starts_with("-- migrate:up\n"));
+        assert!(files[0].1.contains("-- migrate:down\n"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn refinery_style_should_use_next_version() -> Result<()> {
+        let (plan, rollback) = plans().await?;
+        let dir = tempfile::tempdir()?;
+        fs::write(dir.path().join("V3__init.sql"), "").await?;
+        let format = RenovateFormatConfig::default().into();
+        let files = MigrationStyle::Refinery
+            .emit(dir.path(), "todo", &plan, &rollback, format)
+            .await?;
+        assert_eq!(files, vec![dir.path().join("V4__todo.sql")]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn refinery_style_should_reject_non_transactional_steps() -> Result<()> {
+        let (_, rollback) = plans().await?;
+        let plan = MigrationPlan::new(vec![MigrationStep::new(
+            "CREATE INDEX CONCURRENTLY todos_title_idx ON public.todos (title)",
+            "todos_title_idx",
+            "index",
+            MigrationAction::Create,
+            "",
+        )])?;
+        let dir = tempfile::tempdir()?;
+        let format = RenovateFormatConfig::default().into();
+        let err = MigrationStyle::Refinery
+            .emit(dir.path(), "todo", &plan, &rollback, format)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("CREATE INDEX CONCURRENTLY"));
+        assert!(MigrationStyle::Sqlx
+            .emit(dir.path(), "todo", &plan, &rollback, format)
+            .await
+            .is_ok());
+        Ok(())
+    }
+}
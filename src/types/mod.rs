@@ -1,4 +1,12 @@
+mod apply_report;
+mod backfill;
 mod differ;
+mod environment_state;
+mod hooks;
+mod migration_plan;
+mod migration_style;
 mod node_delta;
 mod relation_id;
+mod saved_plan;
+mod schema_filter;
 mod schema_id;
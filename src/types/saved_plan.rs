@@ -0,0 +1,190 @@
+use crate::{DatabaseSchema, MigrationPhase, MigrationPlan, MigrationRecord, SavedPlan};
+use anyhow::{bail, Context, Result};
+use std::path::Path;
+use tokio::fs;
+
+impl SavedPlan {
+    pub fn new(
+        plan: MigrationPlan,
+        rollback: MigrationPlan,
+        target: &DatabaseSchema,
+        remote: bool,
+    ) -> Self {
+        Self {
+            remote,
+            fingerprint: target.fingerprint(),
+            plan,
+            rollback,
+            env: None,
+            preflight: vec![],
+            phase: None,
+        }
+    }
+
+    pub async fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)
+            .await
+            .with_context(|| format!("Failed to read plan: {}", path.display()))?;
+        let plan = serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse plan: {}", path.display()))?;
+        Ok(plan)
+    }
+
+    pub async fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let content = serde_json::to_string_pretty(self)?;
+        fs::write(path, content)
+            .await
+            .with_context(|| format!("Failed to write plan: {}", path.display()))?;
+        Ok(())
+    }
+
+    /// the plan with the steps of the phase only. The preflight results are kept for those
+    /// steps, renumbered to their positions in the phase
+    pub fn for_phase(&self, phase: MigrationPhase) -> Self {
+        let positions: Vec<Option<usize>> = self
+            .plan
+            .steps
+            .iter()
+            .scan(0, |next, step| {
+                Some((step.phase == phase).then(|| {
+                    *next += 1;
+                    *next - 1
+                }))
+            })
+            .collect();
+        let preflight = self
+            .preflight
+            .iter()
+            .filter_map(|result| {
+                let step = positions.get(result.check.step).copied().flatten()?;
+                let mut result = result.clone();
+                result.check.step = step;
+                Some(result)
+            })
+            .collect();
+        Self {
+            plan: self.plan.phase(phase),
+            preflight,
+            phase: Some(phase),
+            ..self.clone()
+        }
+    }
+
+    /// check the pre-deploy phase of the plan is the last migration applied to the target, so
+    /// that the post-deploy phase can follow it. `history` is most recent first
+    pub fn check_pre_applied(
+        &self,
+        history: &[MigrationRecord],
+        target: &DatabaseSchema,
+    ) -> Result<()> {
+        let pre_id = self.for_phase(MigrationPhase::Pre).plan.id();
+        let applied = history
+            .first()
+            .filter(|r| r.plan_id == pre_id && r.phase.as_deref() == Some("pre"));
+        match applied {
+            Some(record) if record.fingerprint == target.fingerprint() => Ok(()),
+            Some(_) => bail!(
+                "The target database has changed since the pre-deploy phase was applied. Please make a new plan."
+            ),
+            None => bail!(
+                "The pre-deploy phase of the plan has not been applied. Run `renovate schema apply --phase pre` first."
+            ),
+        }
+    }
+
+    /// number of the preflight checks which found rows the plan would fail on
+    pub fn preflight_failures(&self) -> usize {
+        self.preflight.iter().filter(|r| r.blocks()).count()
+    }
+
+    /// make sure the target schema is still the one the plan was made against
+    pub fn check_drift(&self, target: &DatabaseSchema) -> Result<()> {
+        let fingerprint = target.fingerprint();
+        if fingerprint != self.fingerprint {
+            bail!(
+                "The target database has changed since the plan was made (fingerprint {} != {}). Please make a new plan.",
+                fingerprint,
+                self.fingerprint
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::todos_schema;
+    use crate::{PreflightCheck, PreflightQuery, PreflightResult, SchemaLoader, SqlLoader};
+
+    #[tokio::test]
+    async fn saved_plan_should_detect_drift() -> Result<()> {
+        let remote = todos_schema().await?;
+        let local = SqlLoader::new("CREATE TABLE public.todos (title text, completed boolean)")
+            .load()
+            .await?;
+        let saved = SavedPlan::new(
+            local.plan(&remote)?,
+            remote.rollback(&local)?,
+            &remote,
+            false,
+        );
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("plan.json");
+        saved.save(&path).await?;
+        let loaded = SavedPlan::load(&path).await?;
+        assert_eq!(saved, loaded);
+
+        assert!(loaded.check_drift(&remote).is_ok());
+        assert!(loaded.check_drift(&local).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn saved_plan_should_split_by_phase() -> Result<()> {
+        let remote = SqlLoader::new("CREATE TABLE public.todos (title text, legacy text)")
+            .load()
+            .await?;
+        let local = SqlLoader::new("CREATE TABLE public.todos (title text NOT NULL, due date)")
+            .load()
+            .await?;
+        let mut saved = SavedPlan::new(
+            local.plan(&remote)?,
+            remote.rollback(&local)?,
+            &remote,
+            false,
+        );
+        let step = saved
+            .plan
+            .steps
+            .iter()
+            .position(|s| s.sql.contains("SET NOT NULL"))
+            .unwrap();
+        saved.preflight = vec![PreflightResult {
+            check: PreflightCheck {
+                step,
+                description: "NULL value(s) in public.todos.(title)".to_owned(),
+                query: PreflightQuery::Count("SELECT 1".to_owned()),
+            },
+            count: Some(3),
+            error: None,
+            sql_state: None,
+        }];
+
+        let pre = saved.for_phase(MigrationPhase::Pre);
+        assert_eq!(pre.phase, Some(MigrationPhase::Pre));
+        assert!(pre.plan.sqls().iter().all(|sql| sql.contains("ADD COLUMN")));
+        assert_eq!(pre.preflight_failures(), 0);
+
+        let post = saved.for_phase(MigrationPhase::Post);
+        assert_eq!(post.plan.len(), 2);
+        assert_eq!(post.preflight_failures(), 1);
+        assert!(post.plan.steps[post.preflight[0].check.step]
+            .sql
+            .contains("SET NOT NULL"));
+        Ok(())
+    }
+}
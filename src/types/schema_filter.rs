@@ -0,0 +1,176 @@
+use crate::{parser::SchemaId, DatabaseSchema, NodeItem, RenovateFilterConfig, SchemaFilter};
+use glob::Pattern;
+use std::collections::BTreeMap;
+
+impl SchemaFilter {
+    pub fn new(include: RenovateFilterConfig, exclude: RenovateFilterConfig) -> Self {
+        Self { include, exclude }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    /// check if the object is managed by renovate. `kind` is the type name of the object, and
+    /// `name` is the schema qualified name of it, or of its table for table level objects
+    pub fn includes(&self, schema: &str, kind: &str, name: &str) -> bool {
+        let include = &self.include;
+        let exclude = &self.exclude;
+        (include.schemas.is_empty() || matches_any(&include.schemas, schema))
+            && (include.types.is_empty() || matches_any(&include.types, kind))
+            && (include.names.is_empty() || matches_any(&include.names, name))
+            && !matches_any(&exclude.schemas, schema)
+            && !matches_any(&exclude.types, kind)
+            && !matches_any(&exclude.names, name)
+    }
+
+    /// the `-n` / `-N` arguments for pg_dump, so that excluded schemas are not dumped at all
+    pub fn pg_dump_args(&self) -> Vec<String> {
+        let include = self.include.schemas.iter().map(|s| format!("-n{}", s));
+        let exclude = self.exclude.schemas.iter().map(|s| format!("-N{}", s));
+        include.chain(exclude).collect()
+    }
+
+    /// remove the objects not managed by renovate from the schema
+    pub fn apply(&self, schema: &mut DatabaseSchema) {
+        if self.is_empty() {
+            return;
+        }
+
+        self.retain_schema_objects(&mut schema.composite_types);
+        self.retain_schema_objects(&mut schema.enum_types);
+        self.retain_schema_objects(&mut schema.sequences);
+        self.retain_schema_objects(&mut schema.tables);
+        self.retain_schema_objects(&mut schema.views);
+        self.retain_schema_objects(&mut schema.mviews);
+        self.retain_schema_objects(&mut schema.functions);
+        for (schema_name, items) in schema.extensions.iter_mut() {
+            items.retain(|name, _| {
+                self.includes(
+                    schema_name,
+                    "extension",
+                    &format!("{}.{}", schema_name, name),
+                )
+            });
+        }
+        schema.extensions.retain(|_, items| !items.is_empty());
+
+        self.retain_table_objects(&mut schema.table_indexes);
+        self.retain_table_objects(&mut schema.table_constraints);
+        self.retain_table_objects(&mut schema.table_sequences);
+        self.retain_table_objects(&mut schema.table_triggers);
+        self.retain_table_objects(&mut schema.table_policies);
+        schema
+            .table_rls
+            .retain(|id, item| self.includes(&id.schema, item.type_name(), &id.to_string()));
+        schema
+            .table_owners
+            .retain(|id, item| self.includes(&id.schema, item.type_name(), &id.to_string()));
+
+        // the id of a privilege is the object it is granted on, or the schema itself
+        for (id, items) in schema.privileges.iter_mut() {
+            let schema_name = id.split('.').next().unwrap_or_default();
+            items.retain(|item| self.includes(schema_name, item.type_name(), id));
+        }
+        schema.privileges.retain(|_, items| !items.is_empty());
+
+        schema.update_schema_names();
+    }
+
+    fn retain_schema_objects<T: NodeItem>(
+        &self,
+        objects: &mut BTreeMap<String, BTreeMap<String, T>>,
+    ) {
+        for (schema_name, items) in objects.iter_mut() {
+            items.retain(|name, item| {
+                self.includes(
+                    schema_name,
+                    item.type_name(),
+                    &format!("{}.{}", schema_name, name),
+                )
+            });
+        }
+        objects.retain(|_, items| !items.is_empty());
+    }
+
+    fn retain_table_objects<T: NodeItem>(
+        &self,
+        objects: &mut BTreeMap<SchemaId, BTreeMap<String, T>>,
+    ) {
+        for (id, items) in objects.iter_mut() {
+            let name = id.to_string();
+            items.retain(|_, item| self.includes(&id.schema, item.type_name(), &name));
+        }
+        objects.retain(|_, items| !items.is_empty());
+    }
+}
+
+fn matches_any(patterns: &[String], s: &str) -> bool {
+    patterns.iter().any(|p| match Pattern::new(p) {
+        Ok(pattern) => pattern.matches(s),
+        Err(_) => p == s,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{SchemaLoader, SqlLoader};
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn filter_should_remove_unmanaged_objects() -> Result<()> {
+        let sql = r#"
+            CREATE TABLE public.todos (id int);
+            CREATE TABLE public.tmp_import (id int);
+            CREATE INDEX todos_id_idx ON public.todos (id);
+            CREATE INDEX tmp_import_id_idx ON public.tmp_import (id);
+            CREATE TABLE tiger.addr (id int);
+            CREATE FUNCTION public.add(a int, b int) RETURNS int LANGUAGE sql AS 'select a + b';
+        "#;
+        let mut schema = SqlLoader::new(sql).load().await?;
+        let filter = SchemaFilter::new(
+            RenovateFilterConfig::default(),
+            RenovateFilterConfig {
+                schemas: vec!["tig*".into()],
+                types: vec!["function".into()],
+                names: vec!["public.tmp_*".into()],
+            },
+        );
+        filter.apply(&mut schema);
+
+        assert_eq!(schema.schemas.iter().collect::<Vec<_>>(), vec!["public"]);
+        assert_eq!(schema.tables["public"].len(), 1);
+        assert!(schema.tables["public"].contains_key("todos"));
+        assert!(schema.functions.is_empty());
+        let indexes: Vec<_> = schema
+            .table_indexes
+            .keys()
+            .map(|id| id.to_string())
+            .collect();
+        assert_eq!(indexes, vec!["public.todos"]);
+        Ok(())
+    }
+
+    #[test]
+    fn include_should_limit_managed_schemas() {
+        let filter = SchemaFilter::new(
+            RenovateFilterConfig {
+                schemas: vec!["public".into(), "app_*".into()],
+                ..Default::default()
+            },
+            RenovateFilterConfig {
+                schemas: vec!["app_legacy".into()],
+                ..Default::default()
+            },
+        );
+        assert!(filter.includes("public", "table", "public.todos"));
+        assert!(filter.includes("app_eu", "view", "app_eu.users"));
+        assert!(!filter.includes("app_legacy", "table", "app_legacy.users"));
+        assert!(!filter.includes("tiger", "table", "tiger.addr"));
+        assert_eq!(
+            filter.pg_dump_args(),
+            vec!["-npublic", "-napp_*", "-Napp_legacy"]
+        );
+    }
+}
@@ -54,13 +54,46 @@ pub fn create_diff_removed<T: NodeItem>(old: &T) -> Result<String> {
     diff_text(&old, &new)
 }
 
+/// generate a plain unified diff for the old and new item, without any terminal styling
+pub fn create_unified_diff<T: NodeItem>(old: Option<&T>, new: Option<&T>) -> String {
+    let format = RenovateFormatConfig::default().into();
+    let fmt = |item: Option<&T>| {
+        item.map(|v| sqlformat::format(&v.to_string(), &Default::default(), format))
+            .unwrap_or_default()
+    };
+    let (old, new) = (fmt(old), fmt(new));
+
+    TextDiff::from_lines(&old, &new)
+        .unified_diff()
+        .missing_newline_hint(false)
+        .to_string()
+}
+
+/// colorize a unified diff for console output
+pub(crate) fn colorize_diff(diff: &str) -> String {
+    diff.lines()
+        .map(|line| {
+            let s = if line.starts_with("@@") {
+                Style::new().cyan()
+            } else if line.starts_with('+') {
+                Style::new().green()
+            } else if line.starts_with('-') {
+                Style::new().red()
+            } else {
+                Style::new().dim()
+            };
+            format!("{}\n", s.apply_to(line))
+        })
+        .collect()
+}
+
 pub(crate) async fn load_config() -> Result<RenovateConfig> {
     let config_file = Path::new("renovate.yml");
     if !config_file.exists() {
         bail!("config file renovate.yml not found in current directory");
     }
     let config = RenovateConfig::load(config_file).await?;
-    Ok(config)
+    Ok(config.with_shadow_url_env())
 }
 
 /// generate the diff between two strings. TODO: this is just for console output for now